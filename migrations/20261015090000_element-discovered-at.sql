@@ -0,0 +1,2 @@
+-- Unix timestamp (seconds) of when the element was first added, NULL for elements added before this was tracked
+ALTER TABLE elements ADD COLUMN discovered_at INTEGER;
@@ -0,0 +1,3 @@
+-- The pair that first produced the element, NULL for base and imported elements
+ALTER TABLE elements ADD COLUMN first_parent TEXT;
+ALTER TABLE elements ADD COLUMN second_parent TEXT;
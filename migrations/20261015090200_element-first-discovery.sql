@@ -0,0 +1,2 @@
+-- `is_new` is the API's "first in the world to find it" flag, not the page's "discovered", so it gets a name that says so
+ALTER TABLE elements RENAME COLUMN is_new TO first_discovery;
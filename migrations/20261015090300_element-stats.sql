@@ -0,0 +1,6 @@
+-- Per-element results from `combine`, so `--only-new` can pick up where the last run left off
+CREATE TABLE IF NOT EXISTS element_stats (
+    result TEXT PRIMARY KEY NOT NULL,
+    -- The element's most recent combinations, oldest first - '1' if it found a new element, '0' if it didn't
+    recent TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Recipe lookups filter by result, and the leaderboard and unused queries look pairs up by either element.
+-- Lookups by `first` already use the primary key's index, so it doesn't need one of its own
+CREATE INDEX IF NOT EXISTS pairs_result ON pairs (result);
+CREATE INDEX IF NOT EXISTS pairs_second ON pairs (second);
@@ -0,0 +1,2 @@
+-- "Nothing" is what the API returns for pairs that don't make anything, so it can't be an element
+DELETE FROM elements WHERE result = 'Nothing';
@@ -0,0 +1,5 @@
+-- Settings that every command should agree on, like which elements count as the base elements
+CREATE TABLE IF NOT EXISTS config (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL
+);
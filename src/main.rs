@@ -1,21 +1,64 @@
-use clap::{Parser, Subcommand};
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use flate2::{write::GzEncoder, Compression};
+use futures::stream::{FuturesUnordered, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
 use rand::{distributions::WeightedIndex, prelude::*};
+use regex::Regex;
 use reqwest::StatusCode;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use sqlx::{prelude::FromRow, SqlitePool};
-use std::{collections::BTreeMap, time::Duration, time::Instant};
+use sqlx::{
+    prelude::FromRow,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    ConnectOptions, Connection, SqliteExecutor, SqlitePool,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Write,
+    io::Write as _,
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::Notify,
+    time::MissedTickBehavior,
+};
 
 #[derive(Debug, Parser)]
 struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    /// The sqlite database to use
+    #[arg(long, global = true, default_value = "infinite-craft.db")]
+    pub db_path: String,
+
+    /// The elements that everything else is made from, used as the roots by `tree` and `path`, e.g. "Water,Fire,Wind,Earth".
+    /// This only applies to the current run - `set-base-elements` saves them for later commands
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub base_elements: Option<Vec<String>>,
+
+    /// The most database connections to keep open at once
+    #[arg(long, global = true, default_value = "5")]
+    pub db_connections: NonZeroU32,
+
+    /// How much to log - error, warn, info, debug or trace
+    #[arg(long, global = true, default_value_t = log::Level::Info)]
+    pub log_level: log::Level,
 }
 
 /// Doc comment
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Run random combinations every 0.5ish seconds to create new elements
-    Combine,
+    Combine(Box<CombineArgs>),
 
     /// Meant to import your existing save from the website into the list of elements in this repo
     ///
@@ -25,10 +68,509 @@ enum Command {
     MergeExistingElements {
         #[arg(short, long)]
         elements_file_path: String,
+
+        /// Overwrite existing elements whose emoji differs from the file, instead of just reporting them
+        #[arg(long)]
+        force: bool,
+
+        /// Report what would be inserted and overwritten without changing the database
+        #[arg(long)]
+        dry_run: bool,
+
+        /// How many elements to save in each transaction
+        #[arg(long, default_value = "500")]
+        batch_size: NonZeroUsize,
+
+        /// Treat names that only differ by case as the same element, keeping the one in the database.
+        /// Only ASCII letters are folded. This can merge elements that really are different, like "Bat" and "BAT"
+        #[arg(long)]
+        case_fold: bool,
+    },
+
+    /// Add Water, Fire, Wind and Earth, the elements the website starts with, skipping any that are already there
+    SeedBase,
+
+    /// Import elements from a plain text file with one element name per line
+    ImportList {
+        #[arg(short, long)]
+        path: String,
+
+        /// The emoji to give every imported element, since the file doesn't have any
+        #[arg(long)]
+        default_emoji: String,
     },
 
     /// Export the data in a way that you can copy into your localstorage and interact with
-    SerializeForPage,
+    SerializeForPage {
+        /// Where to write the export, or `-` for stdout
+        #[arg(short, long, default_value = "serialized_for_page.json")]
+        output: String,
+
+        /// Pretty-print the JSON so it's easier to read
+        #[arg(long)]
+        pretty: bool,
+
+        /// Keep the elements already in the output file, adding the database's elements to them.
+        /// If an element's emoji differs, the database's emoji is used
+        #[arg(long)]
+        append: bool,
+
+        /// Mark every exported element as discovered. Elements from the database always are, so this only
+        /// changes elements kept from the output file with --append
+        #[arg(long)]
+        mark_all_discovered: bool,
+
+        /// Only export the database's elements with one of these emoji, e.g. "🐶,🐱,🐭"
+        #[arg(long, value_delimiter = ',')]
+        emoji: Vec<String>,
+    },
+
+    /// Export just the elements whose names match a regex, in the same format as `serialize-for-page`
+    ExportFiltered {
+        /// e.g. "(?i)pizza|cake|bread" for a food-only save
+        pattern: String,
+
+        /// Where to write the export, or `-` for stdout
+        #[arg(short, long)]
+        output: String,
+
+        /// Pretty-print the JSON so it's easier to read
+        #[arg(long)]
+        pretty: bool,
+    },
+
+    /// Print a summary of the elements and pairs in the database
+    ///
+    /// Opens the database read-only, so it's safe to run while `combine` is running in another process
+    Stats {
+        /// Only count elements discovered within this long, e.g. "24h". Pairs aren't timestamped, so they're always all counted
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+
+        /// Print the stats as a JSON object, e.g. for piping into `jq`
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Find elements whose name contains the given text
+    Search {
+        query: String,
+
+        #[arg(short, long)]
+        case_insensitive: bool,
+    },
+
+    /// List every known pair of elements that combines into the given element
+    Recipe { element: String },
+
+    /// List every pair the given element has been part of, with its partner and what they made
+    History { element: String },
+
+    /// Print the full crafting tree for an element, expanding the shortest known recipe at each step down to the base elements
+    Tree {
+        element: String,
+
+        #[arg(short, long, default_value_t = 10)]
+        max_depth: usize,
+
+        /// Pick a random recipe for each element instead of the shortest one
+        #[arg(long)]
+        random_recipe: bool,
+
+        /// Seed the random recipe picks, so the same seed shows the same tree
+        #[arg(long, requires = "random_recipe")]
+        seed: Option<u64>,
+    },
+
+    /// Find a shortest way to make one element, starting from another element plus the base elements
+    ///
+    /// This searches breadth-first over the known pairs, so it finds a recipe with the fewest rounds of combining
+    Path { from: String, to: String },
+
+    /// Export the crafting graph as a GraphViz DOT file, which can be rendered with e.g. `dot -Tpng`
+    ExportDot {
+        #[arg(short, long)]
+        output: String,
+
+        /// Only include the pair that first discovered each element, instead of every known recipe
+        #[arg(long)]
+        first_discovery_only: bool,
+    },
+
+    /// Export the elements and pairs tables as CSV files, e.g. for loading into a spreadsheet
+    ExportCsv {
+        #[arg(short, long)]
+        elements_path: String,
+
+        #[arg(short, long)]
+        pairs_path: String,
+    },
+
+    /// List the pairs that were made from elements that aren't in the database, e.g. because they were deleted by hand.
+    /// Pairs whose result isn't in the database are counted too, but that's expected with --blocklist or --max-elements
+    CheckIntegrity {
+        /// Delete the pairs made from missing elements
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// Delete every pair that resulted in Nothing, to reclaim space
+    ///
+    /// Deleted pairs are no longer known to be dead ends, so `combine` may try them again
+    PruneNothing {
+        /// Actually delete the pairs, rather than just counting them
+        #[arg(long)]
+        confirm: bool,
+    },
+
+    /// Rewrite the pairs table so every pair is stored in sorted order, merging pairs that were stored both ways round
+    DedupePairs,
+
+    /// List the elements that are part of the most successful pairs
+    Leaderboard {
+        #[arg(short, long, default_value_t = 10)]
+        limit: u32,
+    },
+
+    /// List the elements in the order they were discovered, with the recipe that made each one and how many elements there were after it
+    Replay,
+
+    /// Write a Markdown summary of the database, e.g. for sharing on GitHub
+    Report {
+        #[arg(short, long, default_value = "report.md")]
+        output: String,
+
+        /// Only count elements discovered within this long, e.g. "24h". Pairs aren't timestamped, so they're always all counted
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+    },
+
+    /// Save a consistent copy of the database next to it, named like `infinite-craft-<label>-<timestamp>.db`.
+    /// This is safe to run while `combine` is writing. To restore a snapshot, stop everything that's using the database,
+    /// delete the database's `-wal` and `-shm` files, and copy the snapshot over the database
+    Snapshot { label: String },
+
+    /// List the elements with the shortest (or longest) names
+    TopByLength {
+        /// Show the longest names instead of the shortest
+        #[arg(long)]
+        longest: bool,
+
+        #[arg(short, long, default_value_t = 10)]
+        limit: u32,
+    },
+
+    /// List the elements that aren't part of any successful pair yet
+    Unused,
+
+    /// Compare this database with another one, reporting the elements only one of them has and the pairs they disagree on
+    Diff {
+        other_db: String,
+
+        /// List every difference, rather than just counting them
+        #[arg(long)]
+        full: bool,
+    },
+
+    /// Replace every element's emoji that the website can't show (e.g. an empty one, or plain text) with a placeholder
+    FixEmoji,
+
+    /// Estimate how many pairs of the current elements haven't been tried yet
+    CountPairsRemaining,
+
+    /// Combine two elements, saving the result. Pairs that have been tried before are answered from the database
+    Pair {
+        first: String,
+        second: String,
+
+        #[command(flatten)]
+        client: ClientConfig,
+    },
+
+    /// Combine the first two elements, then combine the result with the next element, and so on, saving everything along the way
+    Chain {
+        #[arg(num_args = 2.., required = true)]
+        elements: Vec<String>,
+
+        #[command(flatten)]
+        client: ClientConfig,
+    },
+
+    /// Request a random sample of the known pairs again, and report any whose result has changed. This doesn't change the database
+    Verify {
+        /// How many pairs to check
+        #[arg(long, default_value_t = 20)]
+        sample: usize,
+
+        /// How long to wait between requests, as with `combine`
+        #[arg(long, default_value_t = 500)]
+        delay_ms: u64,
+
+        #[command(flatten)]
+        client: ClientConfig,
+    },
+
+    /// Request a random sample of the pairs that made Nothing again, saving any that now make an element
+    RetryNothing {
+        /// How many pairs to retry
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+
+        /// How long to wait between requests, as with `combine`
+        #[arg(long, default_value_t = 500)]
+        delay_ms: u64,
+
+        #[command(flatten)]
+        client: ClientConfig,
+    },
+
+    /// Save the base elements that `tree`, `path` and `combine --offline` use when --base-elements isn't passed
+    SetBaseElements {
+        /// The base elements, e.g. "Water,Fire,Wind,Earth". Each one has to be in the database already
+        #[arg(required = true, value_delimiter = ',')]
+        elements: Vec<String>,
+    },
+}
+#[derive(Debug, Args)]
+struct CombineArgs {
+    #[command(flatten)]
+    client: ClientConfig,
+
+    /// How long to wait between combinations
+    #[arg(long, default_value_t = 500)]
+    delay_ms: u64,
+
+    /// Randomly lengthen or shorten each delay by up to this much, so that requests don't go out like clockwork
+    #[arg(long, default_value_t = 0)]
+    jitter_ms: u64,
+
+    /// Stop after this many combinations instead of running forever
+    #[arg(long)]
+    count: Option<u64>,
+
+    /// Stop after running for this long, e.g. "30m" or "2h 30m"
+    #[arg(long, value_parser = humantime::parse_duration)]
+    duration: Option<Duration>,
+
+    /// How many requests to have going at once. Each one still waits `delay_ms` after it finishes
+    #[arg(long, default_value = "1")]
+    concurrency: NonZeroUsize,
+
+    /// How often to log a progress summary
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "60s")]
+    progress_interval: Duration,
+
+    /// Don't make any requests, only combine elements using pairs that have already been tried.
+    /// Starts from the base elements, or from --start-from when it's given
+    #[arg(long)]
+    offline: bool,
+
+    /// Append a JSON line to this file for every combination attempt
+    #[arg(long)]
+    event_log: Option<String>,
+
+    /// Gzip the event log, e.g. to `events.jsonl.gz`. Each run adds a gzip member to the end of the file, which `zcat` reads straight through.
+    /// A run that's killed can't finish its member, so start a new file after one
+    #[arg(long, requires = "event_log")]
+    event_log_gzip: bool,
+
+    /// Append a line to this file for every first-in-the-world discovery, as a permanent record of them
+    #[arg(long, default_value = "discoveries.log")]
+    discoveries_log: String,
+
+    /// How to report new elements. Warnings and errors always go to the log
+    #[arg(long, value_enum, default_value_t = OutputFormat::Log)]
+    output_format: OutputFormat,
+
+    /// Keep going when a combination can't be saved, keeping it in this file instead so the next run with the same file can save it.
+    /// The file has one JSON object per line, with the pair's `first` and `second`, its `result` (null for Nothing),
+    /// and the `newElement` it made, if any (its `emoji`, `firstDiscovery` and `discoveredAt`). It's read and deleted at startup,
+    /// once everything in it has been saved
+    #[arg(long)]
+    recovery_file: Option<String>,
+
+    /// How often to write combinations that couldn't be saved to --recovery-file
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    recovery_interval: Duration,
+
+    /// Never add elements matching this file to the database. Each line is either an exact name, or a regex between slashes like /^.{40,}$/.
+    /// Pairs that make a blocked element are still saved, so they aren't requested again
+    #[arg(long)]
+    blocklist: Option<String>,
+
+    /// Serve Prometheus metrics for the run on this port, at any path
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Bias the sampling towards elements that share a word with this element, to try and reach it
+    #[arg(long)]
+    target: Option<String>,
+
+    /// Stop once the target has been found
+    #[arg(long, requires = "target")]
+    stop_on_target: bool,
+
+    /// How to weight elements when picking which ones to combine
+    #[arg(long, value_enum, default_value_t = WeightStrategy::ShortNames)]
+    strategy: WeightStrategy,
+
+    /// With the short-names strategy, the weight of an empty name. Each character takes one off it
+    #[arg(long, default_value_t = 12)]
+    weight_base: usize,
+
+    /// With the short-names strategy, the lowest weight a name can get, however long it is.
+    /// Setting it to --weight-base or higher makes every element equally likely
+    #[arg(long, default_value_t = 2)]
+    weight_min: usize,
+
+    /// Seed the random number generator, so that the same seed and starting database pick the same pairs.
+    /// This only controls which pairs get picked - the API can still give different results for them
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Stop adding new elements once there are this many. Pairs are still saved, so they aren't requested again
+    #[arg(long)]
+    max_elements: Option<usize>,
+
+    /// Stop combining once --max-elements is reached, instead of carrying on without adding elements
+    #[arg(long, requires = "max_elements")]
+    stop_at_max_elements: bool,
+
+    /// Don't use elements with names longer than this many characters in combinations. They're still saved when they're found
+    #[arg(long)]
+    max_name_length: Option<usize>,
+
+    /// After a pair's requests have failed (after retries) this many times, stop picking it for the rest of the run.
+    /// Nothing is saved for it, so it's picked again in later runs
+    #[arg(long, default_value = "3")]
+    max_pair_failures: NonZeroU32,
+
+    /// Treat results that only differ from an existing element by case as that element, so "sun" doesn't get added next to "Sun".
+    /// The first casing seen is kept, and pairs are saved with it. Only ASCII letters are folded.
+    /// This can merge elements that really are different, like "Bat" and "BAT"
+    #[arg(long)]
+    case_fold: bool,
+
+    /// Never combine an element with itself. By default an element can be picked as both halves of a pair
+    #[arg(long, conflicts_with = "self_combine_first")]
+    no_self_combine: bool,
+
+    /// Add any of Water, Fire, Wind and Earth that are missing before starting, so a new database has something to combine
+    #[arg(long)]
+    seed_base_elements: bool,
+
+    /// Instead of picking random pairs, combine every element with this one, including elements found along the way, then stop.
+    /// Pairs that have already been tried are skipped, as are any that --no-self-combine or --max-name-length rule out
+    #[arg(long, value_name = "PARTNER", conflicts_with = "self_combine_first")]
+    sweep: Option<String>,
+
+    /// Try combining every element with itself before any random pairs, including new elements as they're found
+    #[arg(long)]
+    self_combine_first: bool,
+
+    /// Only combine the elements listed in this file (one name per line) and whatever they lead to, instead of every known element.
+    /// Everything found is still saved as usual
+    #[arg(long)]
+    start_from: Option<String>,
+
+    /// Down-weight elements whose recent combinations haven't found anything new.
+    /// This is only a heuristic - an "exhausted" element can still be part of a new discovery, it just gets picked less often
+    #[arg(long)]
+    only_new: bool,
+
+    /// Start `--only-new` from the results saved by previous runs with this flag, and save this run's results for the next one.
+    /// Without it, `--only-new` starts from scratch and doesn't save anything
+    #[arg(long, requires = "only_new")]
+    resume_weights: bool,
+}
+
+/**
+ *  How to talk to the neal.fun API
+ */
+#[derive(Debug, Clone, Args)]
+struct ClientConfig {
+    /// How many times to retry a failed request, with exponential backoff, before skipping the pair
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+
+    /// How long to wait for a response before giving up on a request and retrying it
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    request_timeout: Duration,
+
+    /// Log a warning for every request that takes longer than this, e.g. "2s", to notice when the API is slowing down
+    #[arg(long, value_parser = humantime::parse_duration)]
+    request_log_latency_threshold: Option<Duration>,
+
+    #[arg(
+        long,
+        default_value = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:122.0) Gecko/20100101 Firefox/122.0"
+    )]
+    user_agent: String,
+
+    #[arg(long, default_value = "https://neal.fun/infinite-craft/")]
+    referer: String,
+
+    /// The most requests to send per second, across all concurrent requests and retries
+    #[arg(long, value_parser = parse_positive_f64)]
+    rps: Option<f64>,
+}
+
+fn parse_positive_f64(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(number) if number > 0.0 && number.is_finite() => Ok(number),
+        _ => Err(format!("{value} isn't a positive number")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum WeightStrategy {
+    /// Favor short names - an element with 1 letter is ~5x more likely to show up than an element with 10+ letters
+    ShortNames,
+    /// Every element is equally likely
+    Uniform,
+    /// Favor recently discovered elements
+    FavorNew,
+    /// Favor elements that are part of few successful pairs
+    FavorRare,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// Log a message for each new element, alongside the rest of the log
+    Log,
+    /// Print a JSON object for each new element to stdout
+    Json,
+    /// Print just the name of each new element to stdout
+    Plain,
+}
+
+impl Command {
+    /// Whether the command only reads from the database, in which case the connection is opened read-only
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Stats { .. }
+                | Command::MergeExistingElements { dry_run: true, .. }
+                | Command::Search { .. }
+                | Command::Recipe { .. }
+                | Command::History { .. }
+                | Command::CheckIntegrity { fix: false }
+                | Command::Tree { .. }
+                | Command::Path { .. }
+                | Command::ExportDot { .. }
+                | Command::ExportFiltered { .. }
+                | Command::ExportCsv { .. }
+                | Command::Leaderboard { .. }
+                | Command::TopByLength { .. }
+                | Command::Report { .. }
+                | Command::Replay
+                | Command::Snapshot { .. }
+                | Command::Unused
+                | Command::CountPairsRemaining
+                | Command::Diff { .. }
+                | Command::Verify { .. }
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, FromRow)]
@@ -36,17 +578,95 @@ enum Command {
 struct Element {
     pub result: String,
     pub emoji: String,
-    pub is_new: bool,
+    /// Whether we were the first in the world to find this element - the API's `isNew`. If the API leaves it out, it's assumed not to be
+    #[serde(rename = "isNew", default)]
+    pub first_discovery: bool,
+    /// Unix timestamp of when the element was added to the database, if it was added after this started being tracked
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub discovered_at: Option<i64>,
+    /// The pair that first produced this element, if it was found by `combine`
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub first_parent: Option<String>,
+    #[serde(skip)]
+    #[sqlx(default)]
+    pub second_parent: Option<String>,
+}
+/// Used in place of emoji that the website can't show
+const DEFAULT_EMOJI: &str = "❓";
+
+/**
+ *  Whether the character is in one of the blocks that emoji come from. This is looser than the real emoji list, but it's
+ *  only used to catch values that obviously aren't emoji, like an empty string or plain text
+ */
+fn is_emoji_character(character: char) -> bool {
+    matches!(
+        character as u32,
+        0x1F000..=0x1FAFF
+            | 0x2190..=0x21FF
+            | 0x2300..=0x23FF
+            | 0x24C2
+            | 0x25A0..=0x25FF
+            | 0x2600..=0x27BF
+            | 0x2900..=0x297F
+            | 0x2B00..=0x2BFF
+            | 0x3030
+            | 0x303D
+            | 0x3297..=0x3299
+            | 0xA9
+            | 0xAE
+            | 0x203C
+            | 0x2049
+            | 0x20E3
+            | 0x2122
+            | 0x2139
+    )
+}
+
+/**
+ *  The API often gives several emoji for one element (e.g. "🎅🏼🦄"), and the website shows them fine, so only values without any emoji are invalid
+ */
+fn is_valid_emoji(emoji: &str) -> bool {
+    emoji.chars().any(is_emoji_character)
+}
+
+/**
+ *  Replaces emoji that the website can't show with a placeholder
+ */
+fn checked_emoji<'a>(element: &str, emoji: &'a str) -> &'a str {
+    if is_valid_emoji(emoji) {
+        emoji
+    } else {
+        log::warn!("{element} has an invalid emoji {emoji:?}, using {DEFAULT_EMOJI} instead");
+        DEFAULT_EMOJI
+    }
 }
+
+/// The result the API gives for pairs that don't make anything. It's reserved - it's stored as a NULL result in `pairs`, and is never an element
+const NOTHING: &str = "Nothing";
+
 impl Element {
-    pub async fn insert(&self, pool: &SqlitePool) {
-        sqlx::query("INSERT INTO elements (result, emoji, is_new) VALUES ($1, $2, $3)")
-            .bind(&self.result)
-            .bind(&self.emoji)
-            .bind(self.is_new)
-            .execute(pool)
-            .await
-            .unwrap();
+    /// Does nothing if the element already exists - `result` is the primary key, so that's enforced by the database.
+    /// `discovered_at` defaults to now if it isn't set
+    pub async fn insert(&self, executor: impl SqliteExecutor<'_>) -> Result<(), sqlx::Error> {
+        if self.result == NOTHING {
+            log::warn!("Not adding {NOTHING} as an element, it's reserved for pairs that don't make anything");
+            return Ok(());
+        }
+        sqlx::query(
+            "INSERT INTO elements (result, emoji, first_discovery, discovered_at, first_parent, second_parent) \
+             VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT(result) DO NOTHING",
+        )
+        .bind(&self.result)
+        .bind(checked_emoji(&self.result, &self.emoji))
+        .bind(self.first_discovery)
+        .bind(self.discovered_at.unwrap_or_else(unix_timestamp))
+        .bind(&self.first_parent)
+        .bind(&self.second_parent)
+        .execute(executor)
+        .await?;
+        Ok(())
     }
 }
 
@@ -68,7 +688,8 @@ impl From<Element> for SerializedElement {
         SerializedElement {
             text: value.result,
             emoji: value.emoji,
-            discovered: value.is_new,
+            // On the page "discovered" means that you have the element, which is true for everything in the database
+            discovered: true,
         }
     }
 }
@@ -77,18 +698,37 @@ impl From<SerializedElement> for Element {
         Element {
             result: value.text,
             emoji: value.emoji,
-            is_new: value.discovered,
+            first_discovery: false,
+            discovered_at: None,
+            first_parent: None,
+            second_parent: None,
         }
     }
 }
 
-fn read_file_as_json<T>(file_path: &str) -> T
+fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn from_unix_timestamp(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+fn read_file_as_json<T>(file_path: &str) -> anyhow::Result<T>
 where
     T: DeserializeOwned,
 {
-    serde_json::from_str(&std::fs::read_to_string(file_path).unwrap()).unwrap()
+    let contents = std::fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read {file_path}"))?;
+    serde_json::from_str(&contents).with_context(|| format!("Failed to parse {file_path}"))
 }
-fn write_file_as_json<T>(file_path: &str, contents: &T, pretty: bool)
+/**
+ *  A file path of `-` prints to stdout instead
+ */
+fn write_file_as_json<T>(file_path: &str, contents: &T, pretty: bool) -> anyhow::Result<()>
 where
     T: Serialize,
 {
@@ -96,198 +736,3752 @@ where
         serde_json::to_string_pretty(contents)
     } else {
         serde_json::to_string(contents)
+    }?;
+    if file_path == "-" {
+        println!("{contents}");
+        Ok(())
+    } else {
+        std::fs::write(file_path, contents).with_context(|| format!("Failed to write {file_path}"))
     }
-    .unwrap();
-    std::fs::write(file_path, contents).unwrap();
 }
 
-async fn insert_pair(pool: &SqlitePool, first: &str, second: &str, result: &Option<String>) {
-    sqlx::query("INSERT INTO pairs (first, second, result) VALUES ($1, $2, $3)")
-        .bind(first)
-        .bind(second)
-        .bind(result)
-        .execute(pool)
-        .await
-        .unwrap();
+/**
+ *  The order doesn't matter when combining, so pairs are always sorted - in requests, in the `pairs` map, and in the database
+ */
+fn normalized_pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
 }
 
-type Elements = BTreeMap<String, Element>;
-type Pairs = BTreeMap<(String, String), Option<String>>;
-
-async fn load(pool: &SqlitePool) -> (Elements, Pairs) {
-    let elements = sqlx::query_as::<_, Element>("SELECT * FROM elements")
-        .fetch_all(pool)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(|element| (element.result.clone(), element))
-        .collect::<Elements>();
-
-    let pairs = sqlx::query_as::<_, (String, String, Option<String>)>("SELECT * FROM pairs")
-        .fetch_all(pool)
-        .await
-        .unwrap()
-        .into_iter()
-        .map(|(first, second, result)| ((first, second), result))
-        .collect::<Pairs>();
-
-    (elements, pairs)
+async fn insert_pair(
+    executor: impl SqliteExecutor<'_>,
+    first: &str,
+    second: &str,
+    result: &Option<String>,
+) -> Result<(), sqlx::Error> {
+    let (first, second) = normalized_pair(first, second);
+    // (first, second) is the primary key, so overlapping runs can't create duplicate rows
+    sqlx::query(
+        "INSERT INTO pairs (first, second, result) VALUES ($1, $2, $3) \
+         ON CONFLICT(first, second) DO NOTHING",
+    )
+    .bind(first)
+    .bind(second)
+    .bind(result)
+    .execute(executor)
+    .await?;
+    Ok(())
 }
 
-async fn serialize_for_page(pool: SqlitePool) {
-    let (elements, _) = load(&pool).await;
+const BUSY_RETRIES: u32 = 5;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(100);
 
-    let elements = SerializedElements {
-        elements: elements
-            .values()
-            .cloned()
-            .map(SerializedElement::from)
-            .collect::<Vec<_>>(),
-    };
-    write_file_as_json("serialized_for_page.json", &elements, false);
+fn is_busy(error: &sqlx::Error) -> bool {
+    // SQLITE_BUSY is 5, and its extended codes keep that in the lowest byte
+    error
+        .as_database_error()
+        .and_then(|error| error.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| code & 0xff == 5)
 }
 
-async fn get_pair_value(client: &reqwest::Client, first: &str, second: &str) -> Option<Element> {
-    let start = Instant::now();
+/**
+ *  Runs a query, retrying it a few times if the database is busy, since another process holding the lock for longer
+ *  than the busy timeout is almost always temporary. Reads can hit this too, e.g. while a checkpoint is running
+ */
+async fn retry_busy<T, F, Fut>(mut query: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match query().await {
+            Err(error) if is_busy(&error) && attempt < BUSY_RETRIES => {
+                attempt += 1;
+                log::warn!("Database is busy, retrying ({attempt}/{BUSY_RETRIES})");
+                tokio::time::sleep(BUSY_RETRY_DELAY * attempt).await;
+            }
+            result => return result,
+        }
+    }
+}
 
-    let response = client
-        .get(format!(
-            "https://neal.fun/api/infinite-craft/pair?first={first}&second={second}"
-        ))
-        .header("Referer", "https://neal.fun/infinite-craft/")
-        .send()
-        .await
-        .unwrap();
+/**
+ *  Saves a combination's pair and the element it made (if it's new) in one transaction
+ */
+async fn write_combination(
+    pool: &SqlitePool,
+    first: &str,
+    second: &str,
+    pair_value: &Option<String>,
+    new_element: Option<&Element>,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    insert_pair(&mut *transaction, first, second, pair_value).await?;
+    if let Some(new_element) = new_element {
+        new_element.insert(&mut *transaction).await?;
+    }
+    transaction.commit().await
+}
 
-    if response.status() != StatusCode::OK {
-        println!("Non-200 status code {response:#?}");
-        panic!("{}", response.text().await.unwrap())
-    } else {
-        let element: Element = serde_json::from_str(&response.text().await.unwrap()).unwrap();
-        let response = if element.result == "Nothing" {
-            None
-        } else {
-            Some(element)
-        };
+const BASE_ELEMENTS: [&str; 4] = ["Water", "Fire", "Wind", "Earth"];
+const BASE_ELEMENTS_CONFIG_KEY: &str = "base_elements";
+/// The emoji the website gives each of `BASE_ELEMENTS`
+const BASE_ELEMENT_EMOJI: [&str; 4] = ["💧", "🔥", "🌬️", "🌍"];
 
-        log::debug!("Request took {} milliseconds", start.elapsed().as_millis());
+/**
+ *  Adds the website's four starting elements, skipping any that are already in the database. Returns how many were added
+ */
+async fn seed_base_elements(pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let mut added = 0;
+    for (result, emoji) in BASE_ELEMENTS.iter().zip(BASE_ELEMENT_EMOJI) {
+        if element_exists(pool, result).await? {
+            log::debug!("{result} is already in the database");
+            continue;
+        }
+        log::info!("Adding {emoji} {result}");
+        Element {
+            result: result.to_string(),
+            emoji: emoji.to_string(),
+            first_discovery: false,
+            discovered_at: None,
+            first_parent: None,
+            second_parent: None,
+        }
+        .insert(pool)
+        .await?;
+        added += 1;
+    }
+    Ok(added)
+}
 
-        response
+async fn set_base_elements(pool: SqlitePool, base_elements: &[String]) -> anyhow::Result<()> {
+    for element in base_elements {
+        if !element_exists(&pool, element).await? {
+            anyhow::bail!("Element {element} not found");
+        }
     }
+
+    sqlx::query(
+        "INSERT INTO config (key, value) VALUES ($1, $2) \
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+    )
+    .bind(BASE_ELEMENTS_CONFIG_KEY)
+    .bind(serde_json::to_string(base_elements)?)
+    .execute(&pool)
+    .await?;
+    println!("Saved the base elements {}", base_elements.join(", "));
+    Ok(())
 }
 
-async fn do_combinations(pool: SqlitePool) {
-    let mut rng = thread_rng();
+/**
+ *  The base elements from `--base-elements` if it was passed, otherwise the saved ones, otherwise the classic four
+ */
+async fn base_elements(
+    pool: &SqlitePool,
+    from_args: Option<Vec<String>>,
+) -> anyhow::Result<Vec<String>> {
+    if let Some(base_elements) = from_args {
+        return Ok(base_elements);
+    }
 
-    let client = reqwest::Client::builder()
-        .user_agent(
-            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:122.0) Gecko/20100101 Firefox/122.0",
-        )
-        .http1_title_case_headers()
-        .build()
-        .unwrap();
+    let saved = sqlx::query_scalar::<_, String>("SELECT value FROM config WHERE key = $1")
+        .bind(BASE_ELEMENTS_CONFIG_KEY)
+        .fetch_optional(pool)
+        .await?;
+    match saved {
+        Some(saved) => serde_json::from_str(&saved)
+            .with_context(|| format!("The saved base elements {saved} are invalid")),
+        None => Ok(BASE_ELEMENTS.map(str::to_string).to_vec()),
+    }
+}
 
-    let (mut elements, mut pairs) = load(&pool).await;
+type Elements = BTreeMap<String, Element>;
+type Pairs = BTreeMap<(String, String), Option<String>>;
 
-    loop {
-        // Weight it towards shorter objects - an element with 1 letter is ~5x more likely to show up than an element with 10+ letters
-        let distribution =
-            WeightedIndex::new(elements.keys().map(|element| 12 - element.len().min(10))).unwrap();
+async fn load(pool: &SqlitePool) -> Result<(Elements, Pairs), sqlx::Error> {
+    let elements =
+        retry_busy(|| sqlx::query_as::<_, Element>("SELECT * FROM elements").fetch_all(pool))
+            .await?
+            .into_iter()
+            .map(|element| (element.result.clone(), element))
+            .collect::<Elements>();
 
-        let (first, second, pair_key) = loop {
-            let index_1 = distribution.sample(&mut rng);
-            let index_2 = distribution.sample(&mut rng);
+    let pairs = retry_busy(|| {
+        sqlx::query_as::<_, (String, String, Option<String>)>("SELECT * FROM pairs").fetch_all(pool)
+    })
+    .await?
+    .into_iter()
+    .map(|(first, second, result)| (normalized_pair(&first, &second), result))
+    .collect::<Pairs>();
 
-            let first = elements.keys().nth(index_1).unwrap();
-            let second = elements.keys().nth(index_2).unwrap();
+    Ok((elements, pairs))
+}
 
-            // Sort pairs so that we don't make the same query twice
-            let pair_key = if first < second {
-                (first.clone(), second.clone())
-            } else {
-                (second.clone(), first.clone())
-            };
+async fn serialize_for_page(
+    pool: SqlitePool,
+    output: &str,
+    pretty: bool,
+    append: bool,
+    mark_all_discovered: bool,
+    emoji: &[String],
+) -> anyhow::Result<()> {
+    let (mut elements, _) = load(&pool).await?;
+    if !emoji.is_empty() {
+        // Emoji are compared without variation selectors, since whether "🌬️" has one depends on where it was copied from
+        let without_variation = |emoji: &str| emoji.replace('\u{fe0f}', "");
+        let emoji = emoji
+            .iter()
+            .map(|emoji| without_variation(emoji))
+            .collect::<HashSet<_>>();
+        elements.retain(|_, element| emoji.contains(&without_variation(&element.emoji)));
+        log::info!("Exporting {} elements with matching emoji", elements.len());
+    }
 
-            if !pairs.contains_key(&pair_key) {
-                break (first, second, pair_key);
+    let mut serialized = BTreeMap::new();
+    if append && output != "-" && std::path::Path::new(output).exists() {
+        for element in read_elements_file(output)?.elements {
+            serialized.insert(element.text.clone(), element);
+        }
+    }
+    for element in elements.into_values().map(SerializedElement::from) {
+        if let Some(existing) = serialized.get(&element.text) {
+            if existing.emoji != element.emoji {
+                log::warn!(
+                    "{} has the emoji {} in {output}, replacing it with {} from the database",
+                    element.text,
+                    existing.emoji,
+                    element.emoji
+                );
             }
-        };
+        }
+        serialized.insert(element.text.clone(), element);
+    }
 
-        let pair_result = get_pair_value(&client, first, second).await;
+    if mark_all_discovered {
+        for element in serialized.values_mut() {
+            element.discovered = true;
+        }
+    }
 
-        // These two statements have to happen together - do not remove or change one without the other
-        pairs.insert(pair_key.clone(), pair_result.clone().map(|p| p.result));
-        insert_pair(
-            &pool,
-            first,
-            second,
-            &pair_result.as_ref().map(|element| element.result.clone()),
-        )
-        .await;
+    let elements = SerializedElements {
+        elements: serialized.into_values().collect(),
+    };
+    write_file_as_json(output, &elements, pretty)
+}
 
-        if let Some(pair_result) = pair_result {
-            if !elements.contains_key(&pair_result.result) {
-                if pair_result.is_new {
-                    log::info!(
-                        "Discovered new element: {} (from {first} and {second})",
+async fn export_filtered(
+    pool: SqlitePool,
+    pattern: &str,
+    output: &str,
+    pretty: bool,
+) -> anyhow::Result<()> {
+    let pattern = Regex::new(pattern).with_context(|| format!("{pattern} isn't a valid regex"))?;
+    let (elements, _) = load(&pool).await?;
+
+    let elements = SerializedElements {
+        elements: elements
+            .into_values()
+            .filter(|element| pattern.is_match(&element.result))
+            .map(SerializedElement::from)
+            .collect(),
+    };
+    log::info!("Exporting {} matching elements", elements.elements.len());
+    write_file_as_json(output, &elements, pretty)
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+async fn export_dot(
+    pool: SqlitePool,
+    output: &str,
+    first_discovery_only: bool,
+) -> anyhow::Result<()> {
+    let (elements, pairs) = load(&pool).await?;
+
+    let edges = if first_discovery_only {
+        elements
+            .values()
+            .filter_map(|element| {
+                Some((
+                    element.first_parent.as_deref()?,
+                    element.second_parent.as_deref()?,
+                    element.result.as_str(),
+                ))
+            })
+            .collect::<Vec<_>>()
+    } else {
+        pairs
+            .iter()
+            .filter_map(|((first, second), result)| {
+                Some((first.as_str(), second.as_str(), result.as_deref()?))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut dot = String::from("digraph elements {\n");
+    for element in elements.values() {
+        writeln!(
+            dot,
+            "    \"{}\" [label=\"{} {}\"];",
+            escape_dot(&element.result),
+            escape_dot(&element.emoji),
+            escape_dot(&element.result)
+        )?;
+    }
+    for (first, second, result) in edges {
+        let result = escape_dot(result);
+        writeln!(dot, "    \"{}\" -> \"{result}\";", escape_dot(first))?;
+        if second != first {
+            writeln!(dot, "    \"{}\" -> \"{result}\";", escape_dot(second))?;
+        }
+    }
+    dot.push_str("}\n");
+
+    std::fs::write(output, dot).with_context(|| format!("Failed to write {output}"))
+}
+
+/**
+ *  Quotes the field if it contains anything that would break RFC 4180 CSV
+ */
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(file_path: &str, header: &[&str], rows: Vec<Vec<String>>) -> anyhow::Result<()> {
+    let mut csv = header.join(",") + "\r\n";
+    for row in rows {
+        csv.push_str(
+            &row.iter()
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        csv.push_str("\r\n");
+    }
+    std::fs::write(file_path, csv).with_context(|| format!("Failed to write {file_path}"))
+}
+
+async fn export_csv(pool: SqlitePool, elements_path: &str, pairs_path: &str) -> anyhow::Result<()> {
+    let (elements, pairs) = load(&pool).await?;
+
+    write_csv(
+        elements_path,
+        &["result", "emoji", "first_discovery", "discovered_at"],
+        elements
+            .into_values()
+            .map(|element| {
+                vec![
+                    element.result,
+                    element.emoji,
+                    element.first_discovery.to_string(),
+                    element
+                        .discovered_at
+                        .map(|discovered_at| discovered_at.to_string())
+                        .unwrap_or_default(),
+                ]
+            })
+            .collect(),
+    )?;
+    write_csv(
+        pairs_path,
+        &["first", "second", "result"],
+        pairs
+            .into_iter()
+            .map(|((first, second), result)| vec![first, second, result.unwrap_or_default()])
+            .collect(),
+    )
+}
+
+async fn prune_nothing(pool: SqlitePool, confirm: bool) -> anyhow::Result<()> {
+    if !confirm {
+        let nothing_count =
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM pairs WHERE result IS NULL")
+                .fetch_one(&pool)
+                .await?;
+        println!("{nothing_count} pairs resulted in Nothing - rerun with --confirm to delete them");
+        return Ok(());
+    }
+
+    let removed = sqlx::query("DELETE FROM pairs WHERE result IS NULL")
+        .execute(&pool)
+        .await?
+        .rows_affected();
+    println!("Removed {removed} pairs that resulted in Nothing");
+    println!("Warning: these pairs may be tried again by the next `combine` run");
+    Ok(())
+}
+
+async fn check_integrity(pool: SqlitePool, fix: bool) -> anyhow::Result<()> {
+    let orphans = sqlx::query_as::<_, (String, String, Option<String>)>(
+        "SELECT * FROM pairs \
+         WHERE NOT EXISTS (SELECT 1 FROM elements WHERE result = pairs.first) \
+            OR NOT EXISTS (SELECT 1 FROM elements WHERE result = pairs.second) \
+         ORDER BY first, second",
+    )
+    .fetch_all(&pool)
+    .await?;
+    let missing_results = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM pairs \
+         WHERE result IS NOT NULL AND NOT EXISTS (SELECT 1 FROM elements WHERE elements.result = pairs.result)",
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    for (first, second, result) in &orphans {
+        println!(
+            "{first} + {second} = {}",
+            result.as_deref().unwrap_or(NOTHING)
+        );
+    }
+    println!(
+        "{} pairs were made from elements that aren't in the database",
+        orphans.len()
+    );
+    println!("{missing_results} pairs made elements that aren't in the database (expected with --blocklist or --max-elements)");
+
+    if !fix {
+        if !orphans.is_empty() {
+            println!("Rerun with --fix to delete the pairs made from missing elements");
+        }
+        return Ok(());
+    }
+
+    let removed = sqlx::query(
+        "DELETE FROM pairs \
+         WHERE NOT EXISTS (SELECT 1 FROM elements WHERE result = pairs.first) \
+            OR NOT EXISTS (SELECT 1 FROM elements WHERE result = pairs.second)",
+    )
+    .execute(&pool)
+    .await?
+    .rows_affected();
+    println!("Removed {removed} pairs");
+    Ok(())
+}
+
+async fn dedupe_pairs(pool: SqlitePool) -> anyhow::Result<()> {
+    let mut transaction = pool.begin().await?;
+
+    let rows = sqlx::query_as::<_, (String, String, Option<String>)>("SELECT * FROM pairs")
+        .fetch_all(&mut *transaction)
+        .await?;
+    let row_count = rows.len();
+
+    let mut pairs = Pairs::new();
+    let mut disagreements = 0;
+    for (first, second, result) in rows {
+        let pair_key = normalized_pair(&first, &second);
+        match pairs.get_mut(&pair_key) {
+            None => {
+                pairs.insert(pair_key, result);
+            }
+            Some(existing) if *existing != result => {
+                log::warn!(
+                    "{} and {} have conflicting results {:?} and {:?}, keeping {:?}",
+                    pair_key.0,
+                    pair_key.1,
+                    existing,
+                    result,
+                    existing.as_ref().or(result.as_ref())
+                );
+                // A result beats Nothing, since the API has clearly been able to combine them
+                if existing.is_none() {
+                    *existing = result;
+                }
+                disagreements += 1;
+            }
+            Some(_) => {}
+        }
+    }
+
+    sqlx::query("DELETE FROM pairs")
+        .execute(&mut *transaction)
+        .await?;
+    for ((first, second), result) in &pairs {
+        insert_pair(&mut *transaction, first, second, result).await?;
+    }
+    transaction.commit().await?;
+
+    println!(
+        "Merged {} duplicate pairs ({disagreements} with conflicting results), {} pairs remain",
+        row_count - pairs.len(),
+        pairs.len()
+    );
+    Ok(())
+}
+
+/**
+ *  The elements that are part of the most successful pairs, with their emoji and how many pairs that is
+ */
+async fn leaderboard(
+    pool: &SqlitePool,
+    limit: u32,
+) -> Result<Vec<(String, Option<String>, i64)>, sqlx::Error> {
+    // Pairs of an element with itself only count once
+    sqlx::query_as::<_, (String, Option<String>, i64)>(
+        "SELECT element, emoji, COUNT(*) AS uses FROM (
+            SELECT first AS element FROM pairs WHERE result IS NOT NULL
+            UNION ALL
+            SELECT second FROM pairs WHERE result IS NOT NULL AND second != first
+        )
+        LEFT JOIN elements ON elements.result = element
+        GROUP BY element
+        ORDER BY uses DESC, element
+        LIMIT $1",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+async fn print_leaderboard(pool: SqlitePool, limit: u32) -> anyhow::Result<()> {
+    let leaderboard = leaderboard(&pool, limit).await?;
+    for (rank, (element, emoji, uses)) in leaderboard.iter().enumerate() {
+        println!(
+            "{:>3}. {} {element} - {uses} successful pairs",
+            rank + 1,
+            emoji.as_deref().unwrap_or("")
+        );
+    }
+    Ok(())
+}
+
+async fn print_top_by_length(pool: SqlitePool, longest: bool, limit: u32) -> anyhow::Result<()> {
+    let order = if longest { "DESC" } else { "ASC" };
+    let elements = sqlx::query_as::<_, (String, String, i64)>(&format!(
+        "SELECT result, emoji, LENGTH(result) AS length FROM elements \
+         ORDER BY length {order}, result \
+         LIMIT $1"
+    ))
+    .bind(limit)
+    .fetch_all(&pool)
+    .await?;
+
+    for (rank, (element, emoji, length)) in elements.iter().enumerate() {
+        println!("{:>3}. {emoji} {element} - {length} characters", rank + 1);
+    }
+    Ok(())
+}
+
+async fn print_unused(pool: SqlitePool) -> anyhow::Result<()> {
+    let unused = sqlx::query_as::<_, Element>(
+        "SELECT * FROM elements WHERE NOT EXISTS (
+            SELECT 1 FROM pairs
+            WHERE (pairs.first = elements.result OR pairs.second = elements.result)
+                AND pairs.result IS NOT NULL
+        )
+        ORDER BY result",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    for element in &unused {
+        println!("{} {}", element.emoji, element.result);
+    }
+    println!("{} unused elements", unused.len());
+    Ok(())
+}
+
+/**
+ *  Reads just the element names and pairs, so `diff` works on any version of the schema without migrating it first
+ */
+async fn load_for_diff(pool: &SqlitePool) -> Result<(BTreeSet<String>, Pairs), sqlx::Error> {
+    let elements = sqlx::query_scalar::<_, String>("SELECT result FROM elements")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .filter(|element| element != NOTHING)
+        .collect::<BTreeSet<_>>();
+
+    let pairs = sqlx::query_as::<_, (String, String, Option<String>)>(
+        "SELECT first, second, result FROM pairs",
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    // Older databases stored failed pairs as "Nothing" rather than NULL
+    .map(|(first, second, result)| {
+        (
+            normalized_pair(&first, &second),
+            result.filter(|result| result != NOTHING),
+        )
+    })
+    .collect::<Pairs>();
+
+    Ok((elements, pairs))
+}
+
+async fn diff_databases(pool: SqlitePool, other_db: &str, full: bool) -> anyhow::Result<()> {
+    let other_pool = SqlitePool::connect_with(
+        SqliteConnectOptions::new()
+            .filename(other_db)
+            .read_only(true)
+            .busy_timeout(DATABASE_BUSY_TIMEOUT),
+    )
+    .await
+    .with_context(|| format!("Failed to open the database {other_db}"))?;
+
+    let (elements, pairs) = load_for_diff(&pool).await?;
+    let (other_elements, other_pairs) = load_for_diff(&other_pool)
+        .await
+        .with_context(|| format!("Failed to read the elements and pairs from {other_db}"))?;
+
+    let only_here = elements.difference(&other_elements).collect::<Vec<_>>();
+    let only_there = other_elements.difference(&elements).collect::<Vec<_>>();
+    let disagreements = pairs
+        .iter()
+        .filter_map(|(pair_key, result)| {
+            let other_result = other_pairs.get(pair_key)?;
+            (result != other_result).then_some((pair_key, result, other_result))
+        })
+        .collect::<Vec<_>>();
+
+    println!("Elements only in this database:   {}", only_here.len());
+    println!("Elements only in the other one:   {}", only_there.len());
+    println!("Pairs with different results:     {}", disagreements.len());
+
+    if full {
+        if !only_here.is_empty() {
+            println!();
+            println!("Only in this database:");
+            for element in &only_here {
+                println!("  {element}");
+            }
+        }
+        if !only_there.is_empty() {
+            println!();
+            println!("Only in {other_db}:");
+            for element in &only_there {
+                println!("  {element}");
+            }
+        }
+        if !disagreements.is_empty() {
+            println!();
+            println!("Different results (this database, then {other_db}):");
+            for ((first, second), result, other_result) in &disagreements {
+                println!(
+                    "  {first} + {second}: {}, {}",
+                    result.as_deref().unwrap_or(NOTHING),
+                    other_result.as_deref().unwrap_or(NOTHING)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn fix_emoji(pool: SqlitePool) -> anyhow::Result<()> {
+    let elements = sqlx::query_as::<_, (String, String)>("SELECT result, emoji FROM elements")
+        .fetch_all(&pool)
+        .await?;
+
+    let mut transaction = pool.begin().await?;
+    let mut fixed = 0;
+    for (result, emoji) in &elements {
+        let checked = checked_emoji(result, emoji);
+        if checked != emoji {
+            sqlx::query("UPDATE elements SET emoji = $1 WHERE result = $2")
+                .bind(checked)
+                .bind(result)
+                .execute(&mut *transaction)
+                .await?;
+            fixed += 1;
+        }
+    }
+    transaction.commit().await?;
+
+    println!("Fixed {fixed} of {} elements", elements.len());
+    Ok(())
+}
+
+async fn print_pairs_remaining(pool: SqlitePool) -> anyhow::Result<()> {
+    let element_count = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM elements")
+        .fetch_one(&pool)
+        .await?;
+    // Pairs involving elements that have since been removed can't be part of the current total
+    let tried = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM pairs \
+         WHERE EXISTS (SELECT 1 FROM elements WHERE result = pairs.first) \
+           AND EXISTS (SELECT 1 FROM elements WHERE result = pairs.second)",
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    // Unordered pairs, including each element with itself
+    let possible = element_count * (element_count + 1) / 2;
+    let remaining = (possible - tried).max(0);
+    let explored = if possible == 0 {
+        0.0
+    } else {
+        tried as f64 / possible as f64 * 100.0
+    };
+
+    println!("Possible pairs:    {possible}");
+    println!("Pairs tried:       {tried}");
+    println!("Pairs remaining:   {remaining}");
+    println!("Explored:          {explored:.2}%");
+    Ok(())
+}
+
+/**
+ *  The earliest discovery time that `--since` includes. Elements without a discovery time are never included
+ */
+fn since_timestamp(since: Option<Duration>) -> Option<i64> {
+    since.map(|since| unix_timestamp() - since.as_secs() as i64)
+}
+
+/**
+ *  What `stats` prints, and the object it prints with `--json`
+ */
+#[derive(Debug, Serialize)]
+struct StatsReport {
+    element_count: i64,
+    pair_count: i64,
+    nothing_count: i64,
+    /// Elements that were first-ever discoveries
+    new_count: i64,
+    /// The percentage of pairs that made an element
+    success_rate: f64,
+    longest: Option<String>,
+    shortest: Option<String>,
+}
+
+async fn print_stats(pool: SqlitePool, since: Option<Duration>, json: bool) -> anyhow::Result<()> {
+    let since = since_timestamp(since);
+    let (element_count, new_count) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COUNT(*), COALESCE(SUM(first_discovery), 0) FROM elements \
+         WHERE $1 IS NULL OR discovered_at >= $1",
+    )
+    .bind(since)
+    .fetch_one(&pool)
+    .await?;
+
+    let (pair_count, nothing_count) =
+        sqlx::query_as::<_, (i64, i64)>("SELECT COUNT(*), COUNT(*) - COUNT(result) FROM pairs")
+            .fetch_one(&pool)
+            .await?;
+
+    let longest = sqlx::query_scalar::<_, String>(
+        "SELECT result FROM elements WHERE $1 IS NULL OR discovered_at >= $1 \
+         ORDER BY LENGTH(result) DESC, result LIMIT 1",
+    )
+    .bind(since)
+    .fetch_optional(&pool)
+    .await?;
+    let shortest = sqlx::query_scalar::<_, String>(
+        "SELECT result FROM elements WHERE $1 IS NULL OR discovered_at >= $1 \
+         ORDER BY LENGTH(result), result LIMIT 1",
+    )
+    .bind(since)
+    .fetch_optional(&pool)
+    .await?;
+
+    let success_rate = if pair_count == 0 {
+        0.0
+    } else {
+        (pair_count - nothing_count) as f64 / pair_count as f64 * 100.0
+    };
+
+    let report = StatsReport {
+        element_count,
+        pair_count,
+        nothing_count,
+        new_count,
+        success_rate,
+        longest,
+        shortest,
+    };
+    if json {
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(());
+    }
+
+    println!("Elements:          {}", report.element_count);
+    println!("New discoveries:   {}", report.new_count);
+    println!("Pairs tried:       {}", report.pair_count);
+    println!("Nothing results:   {}", report.nothing_count);
+    println!("Success rate:      {:.1}%", report.success_rate);
+    println!("Longest element:   {}", report.longest.unwrap_or_default());
+    println!("Shortest element:  {}", report.shortest.unwrap_or_default());
+    Ok(())
+}
+
+async fn replay(pool: SqlitePool) -> anyhow::Result<()> {
+    let untimed =
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM elements WHERE discovered_at IS NULL")
+            .fetch_one(&pool)
+            .await?;
+    let discovered = sqlx::query_as::<_, Element>(
+        "SELECT * FROM elements WHERE discovered_at IS NOT NULL ORDER BY discovered_at, rowid",
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    println!("Starting with {untimed} elements from before discovery times were tracked");
+    for (index, element) in discovered.iter().enumerate() {
+        let discovered_at = from_unix_timestamp(element.discovered_at.unwrap_or_default());
+        let recipe = match (&element.first_parent, &element.second_parent) {
+            (Some(first), Some(second)) => format!(" = {first} + {second}"),
+            _ => String::new(),
+        };
+        println!(
+            "{:>6}  {}  {} {}{recipe}",
+            untimed + index as i64 + 1,
+            humantime::format_rfc3339_seconds(discovered_at),
+            element.emoji,
+            element.result
+        );
+    }
+    Ok(())
+}
+
+const REPORT_TABLE_LENGTH: u32 = 20;
+
+/**
+ *  Element names can contain anything, so escape the characters that would break a Markdown table
+ */
+fn escape_markdown(value: &str) -> String {
+    let mut escaped = String::new();
+    for character in value.chars() {
+        if matches!(
+            character,
+            '\\' | '|' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+async fn write_report(
+    pool: SqlitePool,
+    output: &str,
+    since: Option<Duration>,
+) -> anyhow::Result<()> {
+    let since = since_timestamp(since);
+    let (element_count, first_discoveries) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COUNT(*), COALESCE(SUM(first_discovery), 0) FROM elements \
+         WHERE $1 IS NULL OR discovered_at >= $1",
+    )
+    .bind(since)
+    .fetch_one(&pool)
+    .await?;
+    let (pair_count, nothing_count) =
+        sqlx::query_as::<_, (i64, i64)>("SELECT COUNT(*), COUNT(*) - COUNT(result) FROM pairs")
+            .fetch_one(&pool)
+            .await?;
+    let most_productive = leaderboard(&pool, REPORT_TABLE_LENGTH).await?;
+    let recent = sqlx::query_as::<_, Element>(
+        "SELECT * FROM elements WHERE discovered_at IS NOT NULL AND ($2 IS NULL OR discovered_at >= $2) \
+         ORDER BY discovered_at DESC, result LIMIT $1",
+    )
+    .bind(REPORT_TABLE_LENGTH)
+    .bind(since)
+    .fetch_all(&pool)
+    .await?;
+
+    let mut report = String::from("# Infinite Craft report\n\n");
+    writeln!(report, "| | Count |\n|---|---:|")?;
+    writeln!(report, "| Elements | {element_count} |")?;
+    writeln!(report, "| First discoveries | {first_discoveries} |")?;
+    writeln!(report, "| Pairs tried | {pair_count} |")?;
+    writeln!(report, "| Pairs that made Nothing | {nothing_count} |")?;
+
+    writeln!(report, "\n## Most productive elements\n")?;
+    writeln!(
+        report,
+        "| Rank | Element | Successful pairs |\n|---:|---|---:|"
+    )?;
+    for (rank, (element, emoji, uses)) in most_productive.iter().enumerate() {
+        writeln!(
+            report,
+            "| {} | {} {} | {uses} |",
+            rank + 1,
+            emoji.as_deref().unwrap_or(""),
+            escape_markdown(element)
+        )?;
+    }
+
+    writeln!(report, "\n## Recently discovered elements\n")?;
+    if recent.is_empty() {
+        writeln!(report, "None of the elements have a discovery time yet.")?;
+    } else {
+        writeln!(report, "| Element | Discovered | Recipe |\n|---|---|---|")?;
+        for element in &recent {
+            let discovered_at = from_unix_timestamp(element.discovered_at.unwrap_or_default());
+            let recipe = match (&element.first_parent, &element.second_parent) {
+                (Some(first), Some(second)) => {
+                    format!("{} + {}", escape_markdown(first), escape_markdown(second))
+                }
+                _ => String::new(),
+            };
+            writeln!(
+                report,
+                "| {} {} | {} | {recipe} |",
+                element.emoji,
+                escape_markdown(&element.result),
+                humantime::format_rfc3339_seconds(discovered_at)
+            )?;
+        }
+    }
+
+    std::fs::write(output, report).with_context(|| format!("Failed to write {output}"))
+}
+
+/**
+ *  Where to save a snapshot - next to the database, with the label and the current time added to its name
+ */
+fn snapshot_path(db_path: &str, label: &str) -> std::path::PathBuf {
+    let db_path = std::path::Path::new(db_path);
+    let stem = db_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    // Without the separators, so that the name is valid everywhere
+    let timestamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .replace(['-', ':'], "");
+    db_path.with_file_name(format!("{stem}-{label}-{timestamp}.db"))
+}
+
+async fn snapshot(pool: SqlitePool, db_path: &str, label: &str) -> anyhow::Result<()> {
+    let path = snapshot_path(db_path, label);
+    // VACUUM INTO reads the database in one transaction, so the copy is consistent even if something else is writing
+    sqlx::query("VACUUM INTO $1")
+        .bind(path.to_string_lossy())
+        .execute(&pool)
+        .await
+        .with_context(|| format!("Failed to write the snapshot {}", path.display()))?;
+    log::info!("Saved a snapshot to {}", path.display());
+    Ok(())
+}
+
+async fn search_elements(
+    pool: SqlitePool,
+    query: &str,
+    case_insensitive: bool,
+) -> anyhow::Result<()> {
+    // `instr` rather than `LIKE` because `LIKE` is always case-insensitive in sqlite, and would treat `%` and `_` in the query as wildcards
+    let sql = if case_insensitive {
+        "SELECT * FROM elements WHERE instr(LOWER(result), LOWER($1)) > 0 ORDER BY result"
+    } else {
+        "SELECT * FROM elements WHERE instr(result, $1) > 0 ORDER BY result"
+    };
+    let elements = sqlx::query_as::<_, Element>(sql)
+        .bind(query)
+        .fetch_all(&pool)
+        .await?;
+
+    for element in &elements {
+        if element.first_discovery {
+            println!("{} {} (new)", element.emoji, element.result);
+        } else {
+            println!("{} {}", element.emoji, element.result);
+        }
+    }
+    println!("{} matching elements", elements.len());
+    Ok(())
+}
+
+async fn print_recipes(pool: SqlitePool, element: &str) -> anyhow::Result<()> {
+    let Some(element) = sqlx::query_as::<_, Element>("SELECT * FROM elements WHERE result = $1")
+        .bind(element)
+        .fetch_optional(&pool)
+        .await?
+    else {
+        println!("Element {element} not found");
+        return Ok(());
+    };
+
+    let recipes = sqlx::query_as::<_, (String, String)>(
+        "SELECT first, second FROM pairs WHERE result = $1 ORDER BY first, second",
+    )
+    .bind(&element.result)
+    .fetch_all(&pool)
+    .await?;
+
+    if recipes.is_empty() {
+        println!("No known recipes for {} {}", element.emoji, element.result);
+    } else {
+        println!("{} {} can be made from:", element.emoji, element.result);
+        for (first, second) in &recipes {
+            println!("  {first} + {second}");
+        }
+    }
+    Ok(())
+}
+
+async fn print_history(pool: SqlitePool, element: &str) -> anyhow::Result<()> {
+    let Some(element) = sqlx::query_as::<_, Element>("SELECT * FROM elements WHERE result = $1")
+        .bind(element)
+        .fetch_optional(&pool)
+        .await?
+    else {
+        println!("Element {element} not found");
+        return Ok(());
+    };
+
+    let history = sqlx::query_as::<_, (String, Option<String>)>(
+        "SELECT CASE WHEN first = $1 THEN second ELSE first END AS partner, result FROM pairs \
+         WHERE first = $1 OR second = $1 \
+         ORDER BY partner",
+    )
+    .bind(&element.result)
+    .fetch_all(&pool)
+    .await?;
+
+    if history.is_empty() {
+        println!(
+            "{} {} hasn't been combined yet",
+            element.emoji, element.result
+        );
+    } else {
+        println!(
+            "{} {} has been combined with:",
+            element.emoji, element.result
+        );
+        for (partner, result) in &history {
+            println!("  {partner} = {}", result.as_deref().unwrap_or(NOTHING));
+        }
+    }
+    Ok(())
+}
+
+/**
+ *  Breadth-first search from the starting elements, where each round combines everything reached so far.
+ *  Returns the combinations needed to make `to`, in an order where each one only uses elements that are already available
+ */
+fn shortest_path(pairs: &Pairs, start: &[&str], to: &str) -> Option<Vec<(String, String, String)>> {
+    // How each reached element was first made, or None for the starting elements
+    let mut reached: HashMap<&str, Option<(&str, &str)>> =
+        start.iter().map(|element| (*element, None)).collect();
+    while !reached.contains_key(to) {
+        let round = pairs
+            .iter()
+            .filter_map(|((first, second), result)| {
+                let result = result.as_deref()?;
+                (reached.contains_key(first.as_str())
+                    && reached.contains_key(second.as_str())
+                    && !reached.contains_key(result))
+                .then_some((result, (first.as_str(), second.as_str())))
+            })
+            .collect::<Vec<_>>();
+        if round.is_empty() {
+            return None;
+        }
+        for (result, pair) in round {
+            reached.entry(result).or_insert(Some(pair));
+        }
+    }
+
+    // Walk back from the target, adding each combination after the ones its inputs need
+    fn add_steps<'a>(
+        element: &'a str,
+        reached: &HashMap<&'a str, Option<(&'a str, &'a str)>>,
+        added: &mut HashSet<&'a str>,
+        steps: &mut Vec<(String, String, String)>,
+    ) {
+        let Some((first, second)) = reached[element] else {
+            return;
+        };
+        if !added.insert(element) {
+            return;
+        }
+        add_steps(first, reached, added, steps);
+        add_steps(second, reached, added, steps);
+        steps.push((first.to_string(), second.to_string(), element.to_string()));
+    }
+    let mut steps = Vec::new();
+    add_steps(to, &reached, &mut HashSet::new(), &mut steps);
+    Some(steps)
+}
+
+async fn print_path(
+    pool: SqlitePool,
+    from: &str,
+    to: &str,
+    base_elements: &[String],
+) -> anyhow::Result<()> {
+    let (elements, pairs) = load(&pool).await?;
+    for element in [from, to] {
+        if !elements.contains_key(element) {
+            println!("Element {element} not found");
+            return Ok(());
+        }
+    }
+
+    let start = base_elements
+        .iter()
+        .map(String::as_str)
+        .chain([from])
+        .collect::<Vec<_>>();
+    match shortest_path(&pairs, &start, to) {
+        None => {
+            println!("{to} can't be made from {from} and the base elements with the known pairs")
+        }
+        Some(steps) if steps.is_empty() => println!("{to} is already one of the starting elements"),
+        Some(steps) => {
+            println!(
+                "{to} can be made from {from} in {} combinations:",
+                steps.len()
+            );
+            for (index, (first, second, result)) in steps.iter().enumerate() {
+                println!("  {}. {first} + {second} = {result}", index + 1);
+            }
+        }
+    }
+    Ok(())
+}
+
+type Recipes = HashMap<String, Vec<(String, String)>>;
+
+fn recipes_by_result(pairs: &Pairs) -> Recipes {
+    let mut recipes = Recipes::new();
+    for ((first, second), result) in pairs {
+        if let Some(result) = result {
+            recipes
+                .entry(result.clone())
+                .or_default()
+                .push((first.clone(), second.clone()));
+        }
+    }
+    recipes
+}
+
+/**
+ *  The minimum number of combination steps needed to make each element from the base elements
+ */
+fn recipe_depths(pairs: &Pairs, base_elements: &[String]) -> HashMap<String, usize> {
+    let mut depths = base_elements
+        .iter()
+        .map(|element| (element.clone(), 0))
+        .collect::<HashMap<_, _>>();
+
+    loop {
+        let mut changed = false;
+        for ((first, second), result) in pairs {
+            let Some(result) = result else { continue };
+            if let (Some(first_depth), Some(second_depth)) = (depths.get(first), depths.get(second))
+            {
+                let depth = first_depth.max(second_depth) + 1;
+                if depths.get(result).is_none_or(|&existing| depth < existing) {
+                    depths.insert(result.clone(), depth);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return depths;
+        }
+    }
+}
+
+/**
+ *  Everything `print_tree` needs that stays the same all the way down the tree
+ */
+struct Tree<'a> {
+    elements: &'a Elements,
+    recipes: &'a Recipes,
+    depths: &'a HashMap<String, usize>,
+    base_elements: &'a [String],
+    max_depth: usize,
+    /// Set to pick random recipes instead of the shortest ones
+    rng: Option<StdRng>,
+}
+
+fn print_tree(tree: &mut Tree, element: &str, visited: &mut HashSet<String>, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let emoji = tree
+        .elements
+        .get(element)
+        .map(|element| element.emoji.as_str())
+        .unwrap_or("");
+
+    if visited.contains(element) {
+        println!("{indent}{emoji} {element} (cycle)");
+        return;
+    }
+    if tree
+        .base_elements
+        .iter()
+        .any(|base_element| base_element == element)
+    {
+        println!("{indent}{emoji} {element}");
+        return;
+    }
+
+    let depth_of = |element: &String| tree.depths.get(element).copied().unwrap_or(usize::MAX);
+    let recipes = tree.recipes.get(element);
+    // Random picks stick to recipes that can be made from the base elements and don't loop back on themselves
+    let random_recipe = tree.rng.as_mut().and_then(|rng| {
+        recipes?
+            .iter()
+            .filter(|(first, second)| {
+                [first, second].into_iter().all(|input| {
+                    input != element && tree.depths.contains_key(input) && !visited.contains(input)
+                })
+            })
+            .choose(rng)
+    });
+    let Some((first, second)) = random_recipe.or_else(|| {
+        recipes?
+            .iter()
+            .min_by_key(|(first, second)| depth_of(first).max(depth_of(second)))
+    }) else {
+        println!("{indent}{emoji} {element} (no known recipe)");
+        return;
+    };
+
+    if depth >= tree.max_depth {
+        println!("{indent}{emoji} {element} ...");
+        return;
+    }
+
+    println!("{indent}{emoji} {element}");
+    visited.insert(element.to_string());
+    print_tree(tree, first, visited, depth + 1);
+    print_tree(tree, second, visited, depth + 1);
+    visited.remove(element);
+}
+
+async fn print_element_tree(
+    pool: SqlitePool,
+    element: &str,
+    max_depth: usize,
+    random_recipe: bool,
+    seed: Option<u64>,
+    base_elements: &[String],
+) -> anyhow::Result<()> {
+    let (elements, pairs) = load(&pool).await?;
+
+    if !elements.contains_key(element) {
+        println!("Element {element} not found");
+        return Ok(());
+    }
+
+    let recipes = recipes_by_result(&pairs);
+    let depths = recipe_depths(&pairs, base_elements);
+    let mut tree = Tree {
+        elements: &elements,
+        recipes: &recipes,
+        depths: &depths,
+        base_elements,
+        max_depth,
+        rng: random_recipe.then(|| match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }),
+    };
+    print_tree(&mut tree, element, &mut HashSet::new(), 0);
+    Ok(())
+}
+
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+enum PairRequestError {
+    Request(reqwest::Error),
+    Status(StatusCode, String),
+    /// 429 response, with how long the `Retry-After` header asked us to wait
+    RateLimited(Option<Duration>),
+    /// 200 response that isn't the JSON we expect, with the start of the body
+    InvalidResponse(String),
+    /// Ctrl-C came in before the request could be sent or retried
+    Cancelled,
+}
+impl std::fmt::Display for PairRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PairRequestError::Request(error) => write!(f, "request failed: {error}"),
+            PairRequestError::Status(status, body) => {
+                write!(f, "non-200 status code {status}: {body}")
+            }
+            PairRequestError::RateLimited(_) => write!(f, "rate limited"),
+            PairRequestError::InvalidResponse(body) => write!(f, "unexpected response: {body}"),
+            PairRequestError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+impl std::error::Error for PairRequestError {}
+impl From<reqwest::Error> for PairRequestError {
+    fn from(value: reqwest::Error) -> Self {
+        PairRequestError::Request(value)
+    }
+}
+
+/**
+ *  Something that can tell us what a pair of elements combines into - the neal.fun API, or a fake one for tests
+ */
+trait PairFetcher {
+    async fn fetch(&self, first: &str, second: &str) -> Result<Option<Element>, PairRequestError>;
+}
+
+/**
+ *  Fetches pairs from the neal.fun API
+ */
+struct ApiClient {
+    client: reqwest::Client,
+    config: ClientConfig,
+    rate_limiter: RateLimiter,
+}
+/**
+ *  The one place the HTTP client is set up, so every command that talks to the API sends the same user agent, referer and timeout.
+ *  Build it once per command and reuse it, so that connections get reused too
+ */
+fn build_client(config: &ClientConfig) -> anyhow::Result<reqwest::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::REFERER,
+        config
+            .referer
+            .parse()
+            .with_context(|| format!("Invalid referer {}", config.referer))?,
+    );
+    Ok(reqwest::Client::builder()
+        .user_agent(&config.user_agent)
+        .default_headers(headers)
+        .http1_title_case_headers()
+        .timeout(config.request_timeout)
+        .build()?)
+}
+
+impl ApiClient {
+    pub fn new(config: ClientConfig) -> anyhow::Result<Self> {
+        let client = build_client(&config)?;
+        let rate_limiter = RateLimiter::new(config.rps);
+        Ok(ApiClient {
+            client,
+            config,
+            rate_limiter,
+        })
+    }
+}
+impl PairFetcher for ApiClient {
+    async fn fetch(&self, first: &str, second: &str) -> Result<Option<Element>, PairRequestError> {
+        get_pair_value(
+            &self.client,
+            &self.config,
+            &self.rate_limiter,
+            first,
+            second,
+        )
+        .await
+    }
+}
+
+/**
+ *  Spaces requests out evenly so that no more than `requests_per_second` go out, however many are running at once.
+ *  With no limit, `wait` returns immediately
+ */
+struct RateLimiter {
+    interval: Option<Duration>,
+    next_request: Mutex<Instant>,
+}
+impl RateLimiter {
+    pub fn new(requests_per_second: Option<f64>) -> Self {
+        RateLimiter {
+            interval: requests_per_second.map(|rps| Duration::from_secs_f64(1.0 / rps)),
+            next_request: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub async fn wait(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let slot = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let slot = (*next_request).max(Instant::now());
+            *next_request = slot + interval;
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+}
+
+/**
+ *  Set by the first Ctrl-C. Anything waiting on it - a retry's backoff, a `Retry-After` or the rate limiter - gives up rather than sending another request
+ */
+#[derive(Default)]
+struct StopSignal {
+    stopping: AtomicBool,
+    notify: Notify,
+}
+impl StopSignal {
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /**
+     *  Runs `future` to completion, or returns `None` as soon as a stop is requested
+     */
+    pub async fn unless_stopped<F: std::future::Future>(&self, future: F) -> Option<F::Output> {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Registered before checking the flag, so a stop in between still wakes us
+        notified.as_mut().enable();
+        if self.is_stopping() {
+            return None;
+        }
+        tokio::select! {
+            output = future => Some(output),
+            _ = notified => None,
+        }
+    }
+}
+
+static STOP: LazyLock<StopSignal> = LazyLock::new(StopSignal::default);
+
+/**
+ *  `Retry-After` can either be a number of seconds or an HTTP date
+ */
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        Some(Duration::from_secs(seconds))
+    } else {
+        httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+}
+
+const PAIR_URL: &str = "https://neal.fun/api/infinite-craft/pair";
+/// How much of an unexpected response body to keep for the logs
+const MAX_LOGGED_BODY_LENGTH: usize = 200;
+
+/**
+ *  Turns a 200 response body into the pair's result. Anything that isn't an element (an HTML error page, a changed format) is an error, so the request gets retried
+ */
+fn parse_pair_response(body: &str) -> Result<Option<Element>, PairRequestError> {
+    match serde_json::from_str::<Element>(body) {
+        Ok(element) if element.result == NOTHING => Ok(None),
+        Ok(element) => Ok(Some(element)),
+        Err(error) => {
+            let truncated = body
+                .chars()
+                .take(MAX_LOGGED_BODY_LENGTH)
+                .collect::<String>();
+            log::warn!("Couldn't parse the API's response ({error}): {truncated}");
+            Err(PairRequestError::InvalidResponse(truncated))
+        }
+    }
+}
+
+fn pair_request(client: &reqwest::Client, first: &str, second: &str) -> reqwest::RequestBuilder {
+    // Names can contain spaces, '&', '#' and the like, so they have to be encoded rather than pasted into the URL
+    client
+        .get(PAIR_URL)
+        .query(&[("first", first), ("second", second)])
+}
+
+async fn request_pair_value(
+    client: &reqwest::Client,
+    first: &str,
+    second: &str,
+) -> Result<Option<Element>, PairRequestError> {
+    let start = Instant::now();
+
+    let response = pair_request(client, first, second).send().await?;
+
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        Err(PairRequestError::RateLimited(retry_after))
+    } else if status != StatusCode::OK {
+        Err(PairRequestError::Status(status, response.text().await?))
+    } else {
+        let response = parse_pair_response(&response.text().await?)?;
+
+        log::debug!("Request took {} milliseconds", start.elapsed().as_millis());
+
+        Ok(response)
+    }
+}
+
+/**
+ *  Retries failed requests up to `config.max_retries` times, doubling the wait each time.
+ *  Rate limited requests are retried after the requested wait, and don't count towards `max_retries`.
+ *  Once Ctrl-C is pressed nothing more is sent, and the pair fails with `PairRequestError::Cancelled`
+ */
+async fn get_pair_value(
+    client: &reqwest::Client,
+    config: &ClientConfig,
+    rate_limiter: &RateLimiter,
+    first: &str,
+    second: &str,
+) -> Result<Option<Element>, PairRequestError> {
+    let mut backoff = INITIAL_RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        STOP.unless_stopped(rate_limiter.wait())
+            .await
+            .ok_or(PairRequestError::Cancelled)?;
+        let start = Instant::now();
+        let result = request_pair_value(client, first, second).await;
+        let elapsed = start.elapsed();
+        if config
+            .request_log_latency_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            log::warn!(
+                "Request for {first} and {second} took {} milliseconds",
+                elapsed.as_millis()
+            );
+        }
+        match result {
+            Ok(response) => return Ok(response),
+            Err(PairRequestError::RateLimited(retry_after)) => {
+                let wait = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_WAIT);
+                log::warn!(
+                    "Rate limited, waiting {} seconds before retrying {first} and {second}",
+                    wait.as_secs()
+                );
+                STOP.unless_stopped(tokio::time::sleep(wait))
+                    .await
+                    .ok_or(PairRequestError::Cancelled)?;
+            }
+            Err(error) if attempt < config.max_retries => {
+                attempt += 1;
+                log::warn!(
+                    "Attempt {attempt} for {first} and {second} failed ({error}), retrying in {} seconds",
+                    backoff.as_secs()
+                );
+                STOP.unless_stopped(tokio::time::sleep(backoff))
+                    .await
+                    .ok_or(PairRequestError::Cancelled)?;
+                backoff *= 2;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/**
+ *  The sampling weight of each element, in the same order as `elements`
+ */
+fn element_weights(
+    strategy: WeightStrategy,
+    weight_base: usize,
+    weight_min: usize,
+    elements: &Elements,
+    pairs: &Pairs,
+) -> Vec<usize> {
+    match strategy {
+        WeightStrategy::ShortNames => elements
+            .keys()
+            .map(|element| length_weight(element, weight_base, weight_min))
+            .collect(),
+        WeightStrategy::Uniform => vec![1; elements.len()],
+        WeightStrategy::FavorNew => {
+            // Weighted by discovery order, so the newest element is the most likely. Elements from before discovery times were tracked get the lowest weight
+            let mut discovered = elements
+                .values()
+                .filter_map(|element| Some((element.discovered_at?, &element.result)))
+                .collect::<Vec<_>>();
+            discovered.sort();
+            let ranks = discovered
+                .into_iter()
+                .enumerate()
+                .map(|(index, (_, element))| (element, index + 2))
+                .collect::<HashMap<_, _>>();
+            elements
+                .keys()
+                .map(|element| ranks.get(element).copied().unwrap_or(1))
+                .collect()
+        }
+        WeightStrategy::FavorRare => {
+            let mut successful_pairs = HashMap::<&String, usize>::new();
+            for ((first, second), result) in pairs {
+                if result.is_some() {
+                    *successful_pairs.entry(first).or_default() += 1;
+                    *successful_pairs.entry(second).or_default() += 1;
+                }
+            }
+            let most_successful_pairs = successful_pairs.values().max().copied().unwrap_or(0);
+            elements
+                .keys()
+                .map(|element| {
+                    1 + most_successful_pairs - successful_pairs.get(element).copied().unwrap_or(0)
+                })
+                .collect()
+        }
+    }
+}
+
+/**
+ *  The short-names weight of an element - `weight_base` minus its length, but never less than `weight_min`
+ */
+fn length_weight(name: &str, weight_base: usize, weight_min: usize) -> usize {
+    weight_base.saturating_sub(name.len()).max(weight_min)
+}
+
+const TARGET_WEIGHT_MULTIPLIER: usize = 5;
+/// How many random pairs to pick before giving up on finding an untried one that way
+const MAX_SAMPLING_ATTEMPTS: usize = 1000;
+
+/**
+ *  Every pair of elements with a nonzero weight that `is_untried` accepts
+ */
+fn untried_pairs(
+    elements: &Elements,
+    weights: &[usize],
+    no_self_combine: bool,
+    is_untried: impl Fn(&(String, String)) -> bool,
+) -> Vec<(String, String)> {
+    let candidates = elements
+        .keys()
+        .zip(weights)
+        .filter(|(_, weight)| **weight > 0)
+        .map(|(element, _)| element)
+        .collect::<Vec<_>>();
+    let mut untried = Vec::new();
+    for (index, first) in candidates.iter().enumerate() {
+        for second in &candidates[index..] {
+            if no_self_combine && first == second {
+                continue;
+            }
+            let pair_key = normalized_pair(first, second);
+            if is_untried(&pair_key) {
+                untried.push(pair_key);
+            }
+        }
+    }
+    untried
+}
+
+/**
+ *  Whether the element looks like it might lead to the target - either one contains the other, or they share a word
+ */
+fn is_related_to_target(element: &str, target: &str) -> bool {
+    let element = element.to_lowercase();
+    let target = target.to_lowercase();
+    element.contains(&target)
+        || target.contains(&element)
+        || element.split_whitespace().any(|word| {
+            target
+                .split_whitespace()
+                .any(|target_word| word == target_word)
+        })
+}
+
+const RECENT_RESULTS_WINDOW: usize = 20;
+const EXHAUSTED_WEIGHT_DIVISOR: usize = 10;
+
+/**
+ *  Tracks whether each element's last few combinations found something new, for `--only-new`
+ */
+#[derive(Default)]
+struct RecentResults {
+    by_element: HashMap<String, VecDeque<bool>>,
+}
+impl RecentResults {
+    pub async fn load(pool: &SqlitePool) -> Result<Self, sqlx::Error> {
+        let by_element =
+            sqlx::query_as::<_, (String, String)>("SELECT result, recent FROM element_stats")
+                .fetch_all(pool)
+                .await?
+                .into_iter()
+                .map(|(result, recent)| {
+                    (
+                        result,
+                        recent.chars().map(|found_new| found_new == '1').collect(),
+                    )
+                })
+                .collect();
+        Ok(RecentResults { by_element })
+    }
+
+    /**
+     *  Adds a result to the element's window, returning the window in the form it's saved in
+     */
+    pub fn record(&mut self, element: &str, found_new: bool) -> String {
+        let results = self.by_element.entry(element.to_string()).or_default();
+        if results.len() == RECENT_RESULTS_WINDOW {
+            results.pop_front();
+        }
+        results.push_back(found_new);
+        results
+            .iter()
+            .map(|found_new| if *found_new { '1' } else { '0' })
+            .collect()
+    }
+
+    /**
+     *  Elements that haven't found anything new in a full window are unlikely to, so they're picked less often.
+     *  Elements that have recently found something are picked more often, in proportion to how many things they found
+     */
+    pub fn adjust_weights(&self, weights: &mut [usize], elements: &Elements) {
+        for (weight, element) in weights.iter_mut().zip(elements.keys()) {
+            let Some(results) = self.by_element.get(element) else {
+                continue;
+            };
+            let hits = results.iter().filter(|found_new| **found_new).count();
+            if hits > 0 {
+                *weight *= 1 + hits;
+            } else if results.len() == RECENT_RESULTS_WINDOW {
+                *weight = (*weight / EXHAUSTED_WEIGHT_DIVISOR).max(1);
+            }
+        }
+    }
+}
+
+async fn save_element_stats(
+    executor: impl SqliteExecutor<'_>,
+    element: &str,
+    recent: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO element_stats (result, recent) VALUES ($1, $2) \
+         ON CONFLICT(result) DO UPDATE SET recent = excluded.recent",
+    )
+    .bind(element)
+    .bind(recent)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/**
+ *  One line of the `--event-log` file
+ */
+#[derive(Debug, Serialize)]
+struct CombinationEvent<'a> {
+    timestamp: i64,
+    first: &'a str,
+    second: &'a str,
+    result: Option<&'a str>,
+    /// Whether the API said this was a first-ever discovery
+    is_new: bool,
+    /// Whether the result wasn't in the elements table yet
+    new_element: bool,
+    /// How long the request took, including any retries
+    latency_ms: u128,
+    /// Why the request failed, or why its result couldn't be saved
+    error: Option<String>,
+}
+
+/**
+ *  A new element, as printed with `--output-format json`
+ */
+#[derive(Debug, Serialize)]
+struct NewElementOutput<'a> {
+    result: &'a str,
+    emoji: &'a str,
+    first: &'a str,
+    second: &'a str,
+    /// Whether the API said this was a first-ever discovery
+    is_new: bool,
+}
+
+struct EventLog {
+    writer: Box<dyn std::io::Write + Send>,
+}
+impl EventLog {
+    pub fn open(file_path: &str, gzip: bool) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)
+            .with_context(|| format!("Failed to open the event log {file_path}"))?;
+        let writer: Box<dyn std::io::Write + Send> = if gzip {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        Ok(EventLog { writer })
+    }
+
+    pub fn record(&mut self, event: &CombinationEvent) -> anyhow::Result<()> {
+        let line = serde_json::to_string(event)? + "\n";
+        self.writer.write_all(line.as_bytes())?;
+        // For gzip this ends the compressed block, so a crash only loses the record being written
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Upper bounds of the request latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/**
+ *  Counters for a combine run. These are what the loop keeps track of its progress with, and they're also served to Prometheus with `--metrics-port`
+ */
+#[derive(Default)]
+struct Metrics {
+    requests: AtomicU64,
+    failed_requests: AtomicU64,
+    successful_pairs: AtomicU64,
+    nothing_results: AtomicU64,
+    new_elements: AtomicU64,
+    elements: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_sum_micros: AtomicU64,
+}
+impl Metrics {
+    pub fn record_request(&self, latency: Duration, succeeded: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.failed_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        // Buckets are cumulative, like Prometheus expects
+        for (bucket, upper_bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            if latency.as_secs_f64() <= upper_bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /**
+     *  Combinations that got an answer, whether that was an element or Nothing
+     */
+    pub fn combinations(&self) -> u64 {
+        self.successful_pairs.load(Ordering::Relaxed) + self.nothing_results.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for (name, help, kind, value) in [
+            (
+                "requests_total",
+                "Requests that finished, including ones that failed",
+                "counter",
+                &self.requests,
+            ),
+            (
+                "failed_requests_total",
+                "Requests that failed after all their retries",
+                "counter",
+                &self.failed_requests,
+            ),
+            (
+                "successful_pairs_total",
+                "Pairs that combined into an element",
+                "counter",
+                &self.successful_pairs,
+            ),
+            (
+                "nothing_results_total",
+                "Pairs that combined into Nothing",
+                "counter",
+                &self.nothing_results,
+            ),
+            (
+                "new_elements_total",
+                "Elements found that weren't in the database yet",
+                "counter",
+                &self.new_elements,
+            ),
+            (
+                "elements",
+                "Elements that can currently be combined",
+                "gauge",
+                &self.elements,
+            ),
+        ] {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(output, "# HELP infinite_craft_{name} {help}");
+            let _ = writeln!(output, "# TYPE infinite_craft_{name} {kind}");
+            let _ = writeln!(output, "infinite_craft_{name} {value}");
+        }
+
+        let _ = writeln!(output, "# HELP infinite_craft_request_latency_seconds How long requests took, including retries");
+        let _ = writeln!(
+            output,
+            "# TYPE infinite_craft_request_latency_seconds histogram"
+        );
+        for (bucket, upper_bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                output,
+                "infinite_craft_request_latency_seconds_bucket{{le=\"{upper_bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.requests.load(Ordering::Relaxed);
+        let _ = writeln!(
+            output,
+            "infinite_craft_request_latency_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            output,
+            "infinite_craft_request_latency_seconds_sum {}",
+            self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            output,
+            "infinite_craft_request_latency_seconds_count {count}"
+        );
+        output
+    }
+}
+
+/**
+ *  A very small HTTP server that answers every request with the current metrics
+ */
+async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let mut socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(error) => {
+                log::warn!("Failed to accept a metrics connection: {error}");
+                continue;
+            }
+        };
+        let body = metrics.render();
+        tokio::spawn(async move {
+            // The request itself doesn't matter, but it has to be read before responding or some clients see the connection reset
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(error) = socket.write_all(response.as_bytes()).await {
+                log::debug!("Failed to send metrics: {error}");
+            }
+        });
+    }
+}
+
+/**
+ *  Results that `combine` should never add as elements
+ */
+#[derive(Default)]
+struct Blocklist {
+    names: HashSet<String>,
+    patterns: Vec<Regex>,
+}
+impl Blocklist {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let mut blocklist = Blocklist::default();
+        for line in read_element_list(path)? {
+            match line
+                .strip_prefix('/')
+                .and_then(|line| line.strip_suffix('/'))
+            {
+                Some(pattern) => blocklist.patterns.push(
+                    Regex::new(pattern)
+                        .with_context(|| format!("Invalid pattern {line} in {path}"))?,
+                ),
+                None => {
+                    blocklist.names.insert(line);
+                }
+            }
+        }
+        Ok(blocklist)
+    }
+
+    pub fn is_blocked(&self, element: &str) -> bool {
+        self.names.contains(element)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.is_match(element))
+    }
+}
+
+/**
+ *  One line of the `--recovery-file` - a combination that got an answer, but couldn't be saved
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UnsavedCombination {
+    first: String,
+    second: String,
+    result: Option<String>,
+    /// Set if the result wasn't in the database yet
+    new_element: Option<UnsavedElement>,
+}
+/**
+ *  A new element in the recovery file. Its name is the pair's result, and its parents are the pair
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UnsavedElement {
+    emoji: String,
+    first_discovery: bool,
+    discovered_at: Option<i64>,
+}
+impl UnsavedCombination {
+    pub fn new(
+        first: String,
+        second: String,
+        result: Option<String>,
+        new_element: Option<Element>,
+    ) -> Self {
+        UnsavedCombination {
+            first,
+            second,
+            result,
+            new_element: new_element.map(|element| UnsavedElement {
+                emoji: element.emoji,
+                first_discovery: element.first_discovery,
+                discovered_at: element.discovered_at,
+            }),
+        }
+    }
+
+    pub fn new_element(&self) -> Option<Element> {
+        let new_element = self.new_element.as_ref()?;
+        Some(Element {
+            result: self.result.clone()?,
+            emoji: new_element.emoji.clone(),
+            first_discovery: new_element.first_discovery,
+            discovered_at: new_element.discovered_at,
+            first_parent: Some(self.first.clone()),
+            second_parent: Some(self.second.clone()),
+        })
+    }
+}
+
+/**
+ *  Replaces the recovery file with every combination that hasn't been saved yet. It's written to a temporary file first,
+ *  so a crash part way through never leaves half a file
+ */
+fn write_recovery_file(file_path: &str, unsaved: &[UnsavedCombination]) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    for combination in unsaved {
+        contents += &(serde_json::to_string(combination)? + "\n");
+    }
+    let temporary_path = format!("{file_path}.tmp");
+    std::fs::write(&temporary_path, contents)
+        .with_context(|| format!("Failed to write {temporary_path}"))?;
+    std::fs::rename(&temporary_path, file_path)
+        .with_context(|| format!("Failed to replace the recovery file {file_path}"))
+}
+
+/**
+ *  Saves the combinations left in the recovery file by an earlier run, then deletes it. If any can't be saved, the file is left alone
+ */
+async fn recover_combinations(pool: &SqlitePool, file_path: &str) -> anyhow::Result<()> {
+    if !std::path::Path::new(file_path).exists() {
+        return Ok(());
+    }
+    let contents = std::fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read the recovery file {file_path}"))?;
+    let mut recovered = 0;
+    for (index, line) in contents.lines().enumerate() {
+        let combination: UnsavedCombination = serde_json::from_str(line)
+            .with_context(|| format!("Line {} of {file_path} is invalid", index + 1))?;
+        // It could have been found again since, by this or another process
+        let new_element = match combination.new_element() {
+            Some(element) if !element_exists(pool, &element.result).await? => Some(element),
+            _ => None,
+        };
+        retry_busy(|| {
+            write_combination(
+                pool,
+                &combination.first,
+                &combination.second,
+                &combination.result,
+                new_element.as_ref(),
+            )
+        })
+        .await
+        .with_context(|| format!("Failed to save the combinations in {file_path}"))?;
+        recovered += 1;
+    }
+    std::fs::remove_file(file_path)
+        .with_context(|| format!("Failed to delete the recovery file {file_path}"))?;
+    log::info!("Saved {recovered} combinations from {file_path}");
+    Ok(())
+}
+
+/**
+ *  Appends a first discovery to the discoveries log. These are rare, so the file is only opened when there's something to write
+ */
+fn record_discovery(
+    file_path: &str,
+    element: &Element,
+    first: &str,
+    second: &str,
+) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)
+        .with_context(|| format!("Failed to open the discoveries log {file_path}"))?;
+    writeln!(
+        file,
+        "{} {} {} (from {first} and {second})",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        element.emoji,
+        element.result
+    )?;
+    Ok(())
+}
+
+/**
+ *  Grows `elements` using only pairs that have already been tried, without making any requests.
+ *  Each round combines everything found so far, so the round an element is found in is how many steps it is from the starting elements
+ */
+fn combine_offline(elements: &mut Elements, known_elements: &Elements, pairs: &Pairs) -> usize {
+    let mut found = 0;
+    for round in 1.. {
+        let round_results = pairs
+            .iter()
+            .filter_map(|((first, second), result)| {
+                let result = result.as_ref()?;
+                if elements.contains_key(first)
+                    && elements.contains_key(second)
+                    && !elements.contains_key(result)
+                {
+                    Some((first, second, known_elements.get(result)?))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if round_results.is_empty() {
+            break;
+        }
+
+        for (first, second, element) in round_results {
+            if !elements.contains_key(&element.result) {
+                log::info!(
+                    "Round {round}: {} (from {first} and {second})",
+                    element.result
+                );
+                elements.insert(element.result.clone(), element.clone());
+                found += 1;
+            }
+        }
+    }
+    found
+}
+
+/**
+ *  The elements `--offline` starts from when it isn't given `--start-from` - the base elements that are in the database
+ */
+async fn offline_start(
+    pool: &SqlitePool,
+    known_elements: &Elements,
+    from_args: Option<Vec<String>>,
+) -> anyhow::Result<Elements> {
+    let names = base_elements(pool, from_args).await?;
+    let start = names
+        .iter()
+        .filter_map(|name| Some((name.clone(), known_elements.get(name)?.clone())))
+        .collect::<Elements>();
+    if start.is_empty() {
+        anyhow::bail!(
+            "None of the base elements {} are in the database",
+            names.join(", ")
+        );
+    }
+    Ok(start)
+}
+
+async fn do_combinations(
+    pool: SqlitePool,
+    args: CombineArgs,
+    base_elements: Option<Vec<String>>,
+    fetcher: &impl PairFetcher,
+) -> anyhow::Result<()> {
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let mut event_log = args
+        .event_log
+        .as_deref()
+        .map(|event_log| EventLog::open(event_log, args.event_log_gzip))
+        .transpose()?;
+    let blocklist = args
+        .blocklist
+        .as_deref()
+        .map(Blocklist::load)
+        .transpose()?
+        .unwrap_or_default();
+
+    if args.seed_base_elements {
+        seed_base_elements(&pool).await?;
+    }
+    if let Some(recovery_file) = &args.recovery_file {
+        recover_combinations(&pool, recovery_file).await?;
+    }
+    let (mut elements, mut pairs) = load(&pool).await?;
+    // The migrations remove it, but a database that's been written to by an older version could have it again
+    elements.remove(NOTHING);
+    if elements.len() < 2 {
+        anyhow::bail!(
+            "The database only has {} elements, which isn't enough to combine. \
+             Import a save with `merge-existing-elements`, or rerun with --seed-base-elements to start from Water, Fire, Wind and Earth",
+            elements.len()
+        );
+    }
+
+    // Elements in the database that aren't part of the starting set yet. They're moved over once a combination produces them
+    let mut unreached = Elements::new();
+    if let Some(start_from) = &args.start_from {
+        let names = read_element_list(start_from)?;
+        for name in &names {
+            if !elements.contains_key(name) {
+                log::warn!("{name} from {start_from} isn't in the database, skipping it");
+            }
+        }
+        (elements, unreached) = elements
+            .into_iter()
+            .partition(|(name, _)| names.contains(name));
+        if elements.is_empty() {
+            anyhow::bail!("None of the elements in {start_from} are in the database");
+        }
+        log::info!(
+            "Starting from {} of the {} known elements",
+            elements.len(),
+            elements.len() + unreached.len()
+        );
+    }
+
+    // Every known name by its folded name, for --case-fold. Where the database already has several casings, the first one wins
+    let mut folded_names = HashMap::new();
+    if args.case_fold {
+        for name in elements.keys().chain(unreached.keys()) {
+            folded_names
+                .entry(name.to_ascii_lowercase())
+                .or_insert_with(|| name.clone());
+        }
+    }
+
+    if let Some(partner) = &args.sweep {
+        if !elements.contains_key(partner) {
+            anyhow::bail!(
+                "Can't sweep with {partner}, it isn't one of the elements being combined"
+            );
+        }
+        if args
+            .max_name_length
+            .is_some_and(|max_name_length| partner.chars().count() > max_name_length)
+        {
+            anyhow::bail!("Can't sweep with {partner}, its name is longer than --max-name-length");
+        }
+    }
+
+    if args.offline {
+        let known_elements = elements
+            .iter()
+            .chain(&unreached)
+            .map(|(name, element)| (name.clone(), element.clone()))
+            .collect();
+        // Without --start-from everything is already reached, so rebuild it from the base elements
+        if args.start_from.is_none() {
+            elements = offline_start(&pool, &known_elements, base_elements).await?;
+        }
+        let found = combine_offline(&mut elements, &known_elements, &pairs);
+        log::info!(
+            "Found {found} elements from known pairs, {} elements total",
+            elements.len()
+        );
+        return Ok(());
+    }
+
+    if let Some(target) = &args.target {
+        if elements.contains_key(target) {
+            log::info!("Already have the target {target}");
+            if args.stop_on_target {
+                return Ok(());
+            }
+        }
+    }
+
+    // Stop between iterations on Ctrl-C so the in-memory and database writes never get split up. A second Ctrl-C doesn't wait
+    tokio::spawn(async {
+        tokio::signal::ctrl_c().await.unwrap();
+        log::info!(
+            "Received Ctrl-C, stopping after the current combinations. Press it again to exit now"
+        );
+        STOP.stop();
+        tokio::signal::ctrl_c().await.unwrap();
+        log::warn!(
+            "Received a second Ctrl-C, exiting without waiting for the requests that are out"
+        );
+        std::process::exit(130);
+    });
+
+    // `kill -USR1` logs the session's stats without waiting for the next progress tick
+    let stats_requested = Arc::new(Notify::new());
+    #[cfg(unix)]
+    {
+        let mut signal =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+                .context("Failed to listen for SIGUSR1")?;
+        let stats_requested = stats_requested.clone();
+        tokio::spawn(async move {
+            while signal.recv().await.is_some() {
+                stats_requested.notify_one();
+            }
+        });
+    }
+
+    let metrics = Arc::new(Metrics::default());
+    metrics
+        .elements
+        .store(elements.len() as u64, Ordering::Relaxed);
+    if let Some(metrics_port) = args.metrics_port {
+        let listener = TcpListener::bind(("0.0.0.0", metrics_port))
+            .await
+            .with_context(|| format!("Failed to listen for metrics on port {metrics_port}"))?;
+        log::info!("Serving metrics on port {metrics_port}");
+        tokio::spawn(serve_metrics(listener, metrics.clone()));
+    }
+
+    let mut remaining = args.count;
+    // Only runs with a --count have an end to show progress towards
+    let progress = args.count.map(progress_bar);
+    let mut reached_target = false;
+    let mut logged_max_elements = false;
+    let mut cache_hits = 0;
+    let mut cache_misses = 0;
+    let mut recent_results = if args.resume_weights {
+        RecentResults::load(&pool).await?
+    } else {
+        RecentResults::default()
+    };
+    let start = Instant::now();
+
+    // Requests run concurrently, but picking pairs and handling the results all happens here, one at a time.
+    // Pairs that are being requested are tracked so that two requests never go out for the same pair
+    let mut requests = FuturesUnordered::new();
+    let mut in_flight = HashSet::new();
+    // How many times each pair has failed this run, and the pairs that have failed too often to be picked again
+    let mut pair_failures = HashMap::<(String, String), u32>::new();
+    let mut failing_pairs = HashSet::new();
+    let mut progress_ticker = tokio::time::interval_at(
+        (start + args.progress_interval).into(),
+        args.progress_interval,
+    );
+    progress_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Combinations that couldn't be saved, with --recovery-file. They're written out whenever there are new ones
+    let mut unsaved = Vec::new();
+    let mut unsaved_changed = false;
+    let mut recovery_ticker = tokio::time::interval_at(
+        (start + args.recovery_interval).into(),
+        args.recovery_interval,
+    );
+    recovery_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let at_max_elements = args
+            .max_elements
+            .is_some_and(|max_elements| elements.len() >= max_elements);
+        if at_max_elements && !logged_max_elements {
+            log::info!("Reached the maximum of {} elements", elements.len());
+            logged_max_elements = true;
+        }
+
+        let should_stop = STOP.is_stopping()
+            || remaining == Some(0)
+            || (at_max_elements && args.stop_at_max_elements)
+            || (reached_target && args.stop_on_target)
+            || args
+                .duration
+                .is_some_and(|duration| start.elapsed() >= duration);
+
+        // Set when there aren't any pairs left to request, so the run ends once the requests that are out come back
+        let mut exhausted = false;
+        if !should_stop && requests.len() < args.concurrency.get() {
+            let mut weights = element_weights(
+                args.strategy,
+                args.weight_base,
+                args.weight_min,
+                &elements,
+                &pairs,
+            );
+            if let Some(target) = &args.target {
+                for (weight, element) in weights.iter_mut().zip(elements.keys()) {
+                    if is_related_to_target(element, target) {
+                        *weight *= TARGET_WEIGHT_MULTIPLIER;
+                    }
+                }
+            }
+            if args.only_new {
+                recent_results.adjust_weights(&mut weights, &elements);
+            }
+            if let Some(max_name_length) = args.max_name_length {
+                for (weight, element) in weights.iter_mut().zip(elements.keys()) {
+                    if element.chars().count() > max_name_length {
+                        *weight = 0;
+                    }
+                }
+            }
+            let distribution = WeightedIndex::new(&weights)
+                .context("None of the elements can be combined with the current options")?;
+
+            while requests.len() < args.concurrency.get() && remaining != Some(0) {
+                let untried_pair = if let Some(partner) = &args.sweep {
+                    let untried_sweep_pair = elements
+                        .keys()
+                        .filter(|element| {
+                            !(args.no_self_combine && *element == partner)
+                                && args.max_name_length.is_none_or(|max_name_length| {
+                                    element.chars().count() <= max_name_length
+                                })
+                        })
+                        .map(|element| normalized_pair(element, partner))
+                        .find(|pair_key| {
+                            !pairs.contains_key(pair_key)
+                                && !in_flight.contains(pair_key)
+                                && !failing_pairs.contains(pair_key)
+                        });
+                    // Once every pair has been sent, the loop ends when the last requests come back
+                    match untried_sweep_pair {
+                        Some(pair_key) => Some(pair_key),
+                        None => break,
+                    }
+                } else if args.self_combine_first {
+                    elements
+                        .keys()
+                        .filter(|element| {
+                            args.max_name_length.is_none_or(|max_name_length| {
+                                element.chars().count() <= max_name_length
+                            })
+                        })
+                        .map(|element| normalized_pair(element, element))
+                        .find(|pair_key| {
+                            !pairs.contains_key(pair_key)
+                                && !in_flight.contains(pair_key)
+                                && !failing_pairs.contains(pair_key)
+                        })
+                } else {
+                    None
+                };
+                let is_untried = |pair_key: &(String, String)| {
+                    !pairs.contains_key(pair_key)
+                        && !in_flight.contains(pair_key)
+                        && !failing_pairs.contains(pair_key)
+                };
+                let pair_key = untried_pair.or_else(|| {
+                    (0..MAX_SAMPLING_ATTEMPTS).find_map(|_| {
+                        let index_1 = distribution.sample(&mut rng);
+                        let index_2 = distribution.sample(&mut rng);
+
+                        let first = elements.keys().nth(index_1).unwrap();
+                        let second = elements.keys().nth(index_2).unwrap();
+                        if args.no_self_combine && first == second {
+                            return None;
+                        }
+
+                        // Sort pairs so that we don't make the same query twice
+                        let pair_key = normalized_pair(first, second);
+
+                        // Known pairs are answered from the cache, so they never cost a request
+                        if pairs.contains_key(&pair_key) {
+                            log::debug!("Cache hit for {first} and {second}");
+                            cache_hits += 1;
+                        }
+                        is_untried(&pair_key).then_some(pair_key)
+                    })
+                });
+                // Random picks keep missing once almost every pair has been tried, so go through the ones that are left instead
+                let pair_key = pair_key.or_else(|| {
+                    untried_pairs(&elements, &weights, args.no_self_combine, is_untried)
+                        .choose(&mut rng)
+                        .cloned()
+                });
+                let Some(pair_key) = pair_key else {
+                    exhausted = true;
+                    break;
+                };
+                cache_misses += 1;
+
+                if let Some(remaining) = remaining.as_mut() {
+                    *remaining -= 1;
+                }
+                in_flight.insert(pair_key.clone());
+                let (first, second) = pair_key.clone();
+                // Only touch the rng when there's jitter, so that the same seed still picks the same pairs without it
+                let jitter = if args.jitter_ms > 0 {
+                    rng.gen_range(-(args.jitter_ms as i64)..=args.jitter_ms as i64)
+                } else {
+                    0
+                };
+                let delay = Duration::from_millis(args.delay_ms.saturating_add_signed(jitter));
+                requests.push(async move {
+                    let request_start = Instant::now();
+                    let pair_result = fetcher.fetch(&first, &second).await;
+                    let latency = request_start.elapsed();
+                    // Each request holds onto its slot for the delay, so that every "worker" waits between its requests
+                    STOP.unless_stopped(tokio::time::sleep(delay)).await;
+                    (first, second, pair_key, pair_result, latency)
+                });
+            }
+        }
+
+        if exhausted && requests.is_empty() {
+            log::info!("There are no pairs of the current elements left to try");
+            break;
+        }
+
+        let request = tokio::select! {
+            request = requests.next() => request,
+            _ = progress_ticker.tick() => {
+                let combinations = metrics.combinations();
+                log::info!(
+                    "Progress: {combinations} combinations ({:.1} per minute), {} new elements, {} elements total",
+                    combinations as f64 / start.elapsed().as_secs_f64() * 60.0,
+                    metrics.new_elements.load(Ordering::Relaxed),
+                    elements.len()
+                );
+                continue;
+            }
+            _ = recovery_ticker.tick(), if unsaved_changed => {
+                if let Some(recovery_file) = &args.recovery_file {
+                    write_recovery_file(recovery_file, &unsaved)?;
+                }
+                unsaved_changed = false;
+                continue;
+            }
+            _ = stats_requested.notified() => {
+                log::info!(
+                    "Stats: {} requests ({} failed), {} new elements, {cache_hits} cache hits, {cache_misses} cache misses, running for {}",
+                    metrics.requests.load(Ordering::Relaxed),
+                    metrics.failed_requests.load(Ordering::Relaxed),
+                    metrics.new_elements.load(Ordering::Relaxed),
+                    humantime::format_duration(Duration::from_secs(start.elapsed().as_secs()))
+                );
+                continue;
+            }
+        };
+        let Some(request) = request else {
+            break;
+        };
+        let (first, second, pair_key, pair_result, latency) = request;
+        in_flight.remove(&pair_key);
+        if let Err(PairRequestError::Cancelled) = pair_result {
+            log::info!("Not retrying {first} and {second} since we're stopping");
+            continue;
+        }
+        metrics.record_request(latency, pair_result.is_ok());
+        if let Some(progress) = &progress {
+            progress.inc(1);
+        }
+
+        let mut pair_result = match pair_result {
+            Ok(pair_result) => pair_result,
+            Err(error) => {
+                log::error!("Giving up on {first} and {second}: {error}");
+                let failures = pair_failures.entry(pair_key.clone()).or_default();
+                *failures += 1;
+                if *failures >= args.max_pair_failures.get() {
+                    log::warn!(
+                        "{first} and {second} have failed {failures} times, skipping them for the rest of the run"
+                    );
+                    failing_pairs.insert(pair_key.clone());
+                }
+                if let Some(event_log) = &mut event_log {
+                    event_log.record(&CombinationEvent {
+                        timestamp: unix_timestamp(),
+                        first: &first,
+                        second: &second,
+                        result: None,
+                        is_new: false,
+                        new_element: false,
+                        latency_ms: latency.as_millis(),
+                        error: Some(error.to_string()),
+                    })?;
+                }
+                continue;
+            }
+        };
+        if let Some(element) = &mut pair_result {
+            if let Some(existing) = folded_names.get(&element.result.to_ascii_lowercase()) {
+                if *existing != element.result {
+                    log::debug!("Treating {} as {existing}", element.result);
+                    element.result = existing.clone();
+                }
+            }
+        }
+        let is_new = pair_result
+            .as_ref()
+            .is_some_and(|element| element.first_discovery);
+
+        let pair_value = pair_result.as_ref().map(|element| element.result.clone());
+        if pair_value.is_some() {
+            metrics.successful_pairs.fetch_add(1, Ordering::Relaxed);
+        } else {
+            metrics.nothing_results.fetch_add(1, Ordering::Relaxed);
+        }
+        let blocked = pair_value
+            .as_deref()
+            .is_some_and(|result| blocklist.is_blocked(result));
+        if blocked {
+            log::debug!(
+                "Skipping blocked element {} (from {first} and {second})",
+                pair_value.as_deref().unwrap_or_default()
+            );
+        }
+        let new_element = match pair_result {
+            Some(mut pair_result)
+                if !blocked
+                    && !at_max_elements
+                    && !elements.contains_key(&pair_result.result)
+                    && !unreached.contains_key(&pair_result.result) =>
+            {
+                match args.output_format {
+                    OutputFormat::Log if pair_result.first_discovery => log::info!(
+                        "Discovered new element: {} (from {first} and {second})",
                         pair_result.result
-                    );
-                } else {
-                    log::info!(
+                    ),
+                    OutputFormat::Log => log::info!(
                         "New element: {} (from {first} and {second})",
                         pair_result.result
+                    ),
+                    OutputFormat::Json => {
+                        let output = serde_json::to_string(&NewElementOutput {
+                            result: &pair_result.result,
+                            emoji: &pair_result.emoji,
+                            first: &first,
+                            second: &second,
+                            is_new: pair_result.first_discovery,
+                        })?;
+                        PROGRESS_BARS.suspend(|| println!("{output}"));
+                    }
+                    OutputFormat::Plain => {
+                        PROGRESS_BARS.suspend(|| println!("{}", pair_result.result));
+                    }
+                }
+                if pair_result.first_discovery {
+                    record_discovery(&args.discoveries_log, &pair_result, &first, &second)?;
+                }
+
+                pair_result.discovered_at = Some(unix_timestamp());
+                pair_result.first_parent = Some(first.clone());
+                pair_result.second_parent = Some(second.clone());
+                Some(pair_result)
+            }
+            _ => None,
+        };
+
+        // The pair and any new element are written in one transaction, and only added to the in-memory maps once it's committed,
+        // so the database and the maps can't get out of sync
+        let saved = retry_busy(|| {
+            write_combination(&pool, &first, &second, &pair_value, new_element.as_ref())
+        })
+        .await;
+
+        // Recorded whether or not it was saved, since the request was still made
+        if let Some(event_log) = &mut event_log {
+            event_log.record(&CombinationEvent {
+                timestamp: unix_timestamp(),
+                first: &first,
+                second: &second,
+                result: pair_value.as_deref(),
+                is_new,
+                new_element: new_element.is_some(),
+                latency_ms: latency.as_millis(),
+                error: saved
+                    .as_ref()
+                    .err()
+                    .map(|error| format!("couldn't save: {error}")),
+            })?;
+        }
+
+        match saved {
+            Ok(()) => {}
+            Err(error) if args.recovery_file.is_some() => {
+                log::error!("Couldn't save {first} and {second}, keeping them for the recovery file: {error}");
+                // Still known for the rest of the run, so they aren't requested again
+                pairs.insert(pair_key, pair_value.clone());
+                unsaved.push(UnsavedCombination::new(
+                    first,
+                    second,
+                    pair_value,
+                    new_element,
+                ));
+                unsaved_changed = true;
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        }
+
+        pairs.insert(pair_key, pair_value.clone());
+        if args.only_new {
+            // A pair of an element with itself is still only one result for it
+            let elements_in_pair = if first == second {
+                vec![&first]
+            } else {
+                vec![&first, &second]
+            };
+            for element in elements_in_pair {
+                let recent = recent_results.record(element, new_element.is_some());
+                // These are only used for weighting, so they aren't in the combination's transaction, and failing to save them isn't fatal
+                if args.resume_weights {
+                    if let Err(error) =
+                        retry_busy(|| save_element_stats(&pool, element, &recent)).await
+                    {
+                        log::warn!("Couldn't save the recent results for {element}: {error}");
+                    }
+                }
+            }
+        }
+
+        let reached_element = match new_element {
+            Some(new_element) => {
+                metrics.new_elements.fetch_add(1, Ordering::Relaxed);
+                Some(new_element)
+            }
+            None => {
+                let reached = pair_value
+                    .as_ref()
+                    .filter(|_| !blocked && !at_max_elements)
+                    .and_then(|result| unreached.remove(result));
+                if let Some(reached) = &reached {
+                    log::info!(
+                        "Reached known element: {} (from {first} and {second})",
+                        reached.result
                     );
                 }
+                reached
+            }
+        };
+        if let Some(reached_element) = reached_element {
+            if args.target.as_ref() == Some(&reached_element.result) {
+                log::info!("🎉 Reached the target {}!", reached_element.result);
+                reached_target = true;
+            }
 
-                // These two statements have to happen together - do not remove or change one without the other
-                pair_result.insert(&pool).await;
-                elements.insert(pair_result.result.clone(), pair_result);
+            if args.case_fold {
+                folded_names
+                    .entry(reached_element.result.to_ascii_lowercase())
+                    .or_insert_with(|| reached_element.result.clone());
             }
+            elements.insert(reached_element.result.clone(), reached_element);
+            metrics
+                .elements
+                .store(elements.len() as u64, Ordering::Relaxed);
         }
+    }
 
-        std::thread::sleep(Duration::from_millis(500));
+    if let (Some(recovery_file), false) = (&args.recovery_file, unsaved.is_empty()) {
+        if unsaved_changed {
+            write_recovery_file(recovery_file, &unsaved)?;
+        }
+        log::warn!(
+            "{} combinations couldn't be saved, rerun with --recovery-file {recovery_file} to save them",
+            unsaved.len()
+        );
     }
+    if let Some(progress) = progress {
+        progress.finish_and_clear();
+    }
+    log::info!(
+        "Performed {} combinations this session, finding {} new elements",
+        metrics.combinations(),
+        metrics.new_elements.load(Ordering::Relaxed)
+    );
+    log::info!("{cache_hits} pairs were already known, {cache_misses} needed a request");
+    Ok(())
+}
+
+/**
+ *  Reads a file in the localstorage format, with errors that point at what's wrong with it, since it's pasted in by hand
+ */
+fn read_elements_file(file_path: &str) -> anyhow::Result<SerializedElements> {
+    let contents: serde_json::Value = read_file_as_json(file_path)?;
+
+    let Some(entries) = contents
+        .get("elements")
+        .and_then(|elements| elements.as_array())
+    else {
+        anyhow::bail!(
+            "{file_path} doesn't look like an elements file - expected an object with an `elements` array, like {{\"elements\": [...]}}"
+        );
+    };
+
+    let elements = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            SerializedElement::deserialize(entry).with_context(|| {
+                format!("Element {index} in {file_path} is invalid - each element needs a `text`, `emoji` and `discovered`")
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(SerializedElements { elements })
 }
 
-async fn merge_existing_elements(pool: SqlitePool, elements_file_path: &str) {
-    let new_elements: SerializedElements = read_file_as_json(elements_file_path);
+/**
+ *  What happened to one batch of a merge
+ */
+#[derive(Default)]
+struct MergeBatch {
+    inserted: usize,
+    already_present: usize,
+    conflicts: Vec<(Element, Element)>,
+}
 
-    for element in new_elements.elements.into_iter().map(Element::from) {
-        if let Some(matching_element) =
-            sqlx::query_as::<_, Element>("SELECT * FROM elements WHERE result = $1")
-                .bind(&element.result)
-                .fetch_optional(&pool)
-                .await
-                .unwrap()
+/**
+ *  Merges some elements in one transaction, so a failure never leaves a batch half done.
+ *  Anything from an earlier batch that was committed is already present, so a rerun skips through it quickly
+ */
+async fn merge_batch(
+    pool: &SqlitePool,
+    batch: &[Element],
+    force: bool,
+    dry_run: bool,
+    case_fold: bool,
+) -> Result<MergeBatch, sqlx::Error> {
+    // NOCASE only folds ASCII letters, the same as `--case-fold` in combine
+    let sql = if case_fold {
+        "SELECT * FROM elements WHERE result = $1 COLLATE NOCASE ORDER BY rowid LIMIT 1"
+    } else {
+        "SELECT * FROM elements WHERE result = $1"
+    };
+    let mut transaction = pool.begin().await?;
+    let mut outcome = MergeBatch::default();
+    for element in batch {
+        if let Some(matching_element) = sqlx::query_as::<_, Element>(sql)
+            .bind(&element.result)
+            .fetch_optional(&mut *transaction)
+            .await?
         {
-            if matching_element != element {
-                panic!(
-                    "Non-matching elements despite matching names\n{:?}\n{:?}",
-                    matching_element, element
-                )
+            // An invalid emoji in the file would only be replaced with the placeholder, so it never overwrites the database's
+            if matching_element.emoji != element.emoji && is_valid_emoji(&element.emoji) {
+                if dry_run {
+                    log::info!("Conflict on {}", element.result);
+                } else if force {
+                    log::info!("Overwriting {}", matching_element.result);
+                    sqlx::query("UPDATE elements SET emoji = $1 WHERE result = $2")
+                        .bind(&element.emoji)
+                        .bind(&matching_element.result)
+                        .execute(&mut *transaction)
+                        .await?;
+                }
+                outcome.conflicts.push((matching_element, element.clone()));
+            } else {
+                outcome.already_present += 1;
             }
+        } else if dry_run {
+            log::info!("Would insert {}", element.result);
+            outcome.inserted += 1;
         } else {
             log::info!("Inserting {}", element.result);
-            element.insert(&pool).await;
+            element.insert(&mut *transaction).await?;
+            outcome.inserted += 1;
         }
     }
+    if !dry_run {
+        transaction.commit().await?;
+    }
+    Ok(outcome)
 }
 
-#[tokio::main]
-async fn main() {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
+async fn merge_existing_elements(
+    pool: SqlitePool,
+    elements_file_path: &str,
+    force: bool,
+    dry_run: bool,
+    batch_size: NonZeroUsize,
+    case_fold: bool,
+) -> anyhow::Result<()> {
+    // The emoji are checked when they're saved, so that the comparisons with the database use what's actually in the file
+    let new_elements = read_elements_file(elements_file_path)?
+        .elements
+        .into_iter()
+        .map(Element::from)
+        .collect::<Vec<_>>();
+    let progress = progress_bar(new_elements.len() as u64);
+
+    let mut inserted = 0;
+    let mut already_present = 0;
+    let mut conflicts = Vec::new();
+    for batch in new_elements.chunks(batch_size.get()) {
+        let outcome = retry_busy(|| merge_batch(&pool, batch, force, dry_run, case_fold))
+            .await
+            .context("Failed to merge a batch of elements - the batches before it were saved, so rerunning will pick up from here")?;
+        inserted += outcome.inserted;
+        already_present += outcome.already_present;
+        conflicts.extend(outcome.conflicts);
+        progress.inc(batch.len() as u64);
+    }
+    progress.finish_and_clear();
+
+    if dry_run {
+        println!(
+            "{inserted} to insert, {} conflicts, {already_present} already present",
+            conflicts.len()
+        );
+        return Ok(());
+    }
+
+    if !conflicts.is_empty() {
+        if force {
+            log::warn!("Overwrote {} conflicting elements:", conflicts.len());
+        } else {
+            log::warn!(
+                "Skipped {} elements that don't match the database (rerun with --force to overwrite them):",
+                conflicts.len()
+            );
+        }
+        for (existing, imported) in &conflicts {
+            log::warn!(
+                "  {}: existing {}, imported {}",
+                existing.result,
+                existing.emoji,
+                imported.emoji
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn verify_pairs(
+    pool: SqlitePool,
+    sample: usize,
+    delay: Duration,
+    fetcher: &impl PairFetcher,
+) -> anyhow::Result<()> {
+    let pairs = sqlx::query_as::<_, (String, String, Option<String>)>(
+        "SELECT * FROM pairs ORDER BY RANDOM() LIMIT $1",
+    )
+    .bind(sample as i64)
+    .fetch_all(&pool)
+    .await?;
+
+    let mut discrepancies = 0;
+    let mut failures = 0;
+    for (index, (first, second, stored)) in pairs.iter().enumerate() {
+        if index > 0 {
+            tokio::time::sleep(delay).await;
+        }
+        let fresh = match fetcher.fetch(first, second).await {
+            Ok(fresh) => fresh.map(|element| element.result),
+            Err(error) => {
+                log::error!("Couldn't check {first} and {second}: {error}");
+                failures += 1;
+                continue;
+            }
+        };
+        if fresh != *stored {
+            println!(
+                "{first} + {second}: stored {}, now {}",
+                stored.as_deref().unwrap_or(NOTHING),
+                fresh.as_deref().unwrap_or(NOTHING)
+            );
+            discrepancies += 1;
+        }
+    }
+
+    println!(
+        "Checked {} pairs, {discrepancies} have changed",
+        pairs.len() - failures
+    );
+    if failures > 0 {
+        println!("{failures} pairs couldn't be checked");
+    }
+    Ok(())
+}
+
+async fn retry_nothing(
+    pool: SqlitePool,
+    limit: usize,
+    delay: Duration,
+    fetcher: &impl PairFetcher,
+) -> anyhow::Result<()> {
+    let pairs = sqlx::query_as::<_, (String, String)>(
+        "SELECT first, second FROM pairs WHERE result IS NULL ORDER BY RANDOM() LIMIT $1",
+    )
+    .bind(limit as i64)
+    .fetch_all(&pool)
+    .await?;
+
+    let mut recovered = 0;
+    let mut new_elements = 0;
+    let mut failures = 0;
+    for (index, (first, second)) in pairs.iter().enumerate() {
+        if index > 0 {
+            tokio::time::sleep(delay).await;
+        }
+        let element = match fetcher.fetch(first, second).await {
+            Ok(Some(element)) => element,
+            Ok(None) => continue,
+            Err(error) => {
+                log::error!("Couldn't retry {first} and {second}: {error}");
+                failures += 1;
+                continue;
+            }
+        };
+
+        println!("{first} + {second} = {}", element.result);
+        recovered += 1;
+        let new_element = if element_exists(&pool, &element.result).await? {
+            None
+        } else {
+            log::info!("New element: {}", element.result);
+            new_elements += 1;
+            Some(Element {
+                discovered_at: Some(unix_timestamp()),
+                first_parent: Some(first.clone()),
+                second_parent: Some(second.clone()),
+                ..element.clone()
+            })
+        };
+        retry_busy(|| update_pair(&pool, first, second, &element.result, new_element.as_ref()))
+            .await?;
+    }
+
+    println!(
+        "Retried {} pairs, {recovered} now make an element ({new_elements} new)",
+        pairs.len() - failures
+    );
+    if failures > 0 {
+        println!("{failures} pairs couldn't be retried");
+    }
+    Ok(())
+}
+
+/**
+ *  Replaces a known pair's result, saving the element it now makes (if it's new) in the same transaction
+ */
+async fn update_pair(
+    pool: &SqlitePool,
+    first: &str,
+    second: &str,
+    result: &str,
+    new_element: Option<&Element>,
+) -> Result<(), sqlx::Error> {
+    let (first, second) = normalized_pair(first, second);
+    let mut transaction = pool.begin().await?;
+    sqlx::query("UPDATE pairs SET result = $3 WHERE first = $1 AND second = $2")
+        .bind(first)
+        .bind(second)
+        .bind(result)
+        .execute(&mut *transaction)
+        .await?;
+    if let Some(new_element) = new_element {
+        new_element.insert(&mut *transaction).await?;
+    }
+    transaction.commit().await
+}
+
+/**
+ *  The result of a pair if it's been tried before - `Some(None)` means it's known to make Nothing
+ */
+async fn known_pair(
+    pool: &SqlitePool,
+    first: &str,
+    second: &str,
+) -> Result<Option<Option<String>>, sqlx::Error> {
+    let (first, second) = normalized_pair(first, second);
+    sqlx::query_scalar::<_, Option<String>>(
+        "SELECT result FROM pairs WHERE first = $1 AND second = $2",
+    )
+    .bind(first)
+    .bind(second)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn combine_chain(
+    pool: SqlitePool,
+    elements: &[String],
+    fetcher: &impl PairFetcher,
+) -> anyhow::Result<()> {
+    // Only the results along the way can be new, so that typos aren't saved as pairs
+    for element in elements {
+        if !element_exists(&pool, element).await? {
+            anyhow::bail!("Element {element} not found");
+        }
+    }
+
+    let mut current = elements[0].clone();
+    for next in &elements[1..] {
+        let result = match known_pair(&pool, &current, next).await? {
+            Some(result) => result,
+            None => {
+                let (first, second) = normalized_pair(&current, next);
+                let result = fetcher
+                    .fetch(&first, &second)
+                    .await
+                    .with_context(|| format!("Failed to combine {current} and {next}"))?;
+                let pair_value = result.as_ref().map(|element| element.result.clone());
+                let new_element = match result {
+                    Some(mut element) if !element_exists(&pool, &element.result).await? => {
+                        log::info!("New element: {}", element.result);
+                        element.discovered_at = Some(unix_timestamp());
+                        element.first_parent = Some(first.clone());
+                        element.second_parent = Some(second.clone());
+                        Some(element)
+                    }
+                    _ => None,
+                };
+                retry_busy(|| {
+                    write_combination(&pool, &current, next, &pair_value, new_element.as_ref())
+                })
+                .await?;
+                pair_value
+            }
+        };
+
+        let Some(result) = result else {
+            println!("{current} + {next} = Nothing");
+            return Ok(());
+        };
+        println!("{current} + {next} = {result}");
+        current = result;
+    }
+    Ok(())
+}
+
+async fn element_exists(pool: &SqlitePool, element: &str) -> Result<bool, sqlx::Error> {
+    Ok(sqlx::query("SELECT 1 FROM elements WHERE result = $1")
+        .bind(element)
+        .fetch_optional(pool)
+        .await?
+        .is_some())
+}
+
+/**
+ *  Reads a plain text file with one element name per line, ignoring blank lines
+ */
+fn read_element_list(path: &str) -> anyhow::Result<Vec<String>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+async fn import_list(pool: SqlitePool, path: &str, default_emoji: &str) -> anyhow::Result<()> {
+    let mut inserted = 0;
+    let mut skipped = 0;
+    for name in read_element_list(path)? {
+        let name = name.as_str();
+        if element_exists(&pool, name).await? {
+            log::info!("Skipping {name}, it already exists");
+            skipped += 1;
+            continue;
+        }
+
+        log::info!("Inserting {name}");
+        Element {
+            result: name.to_string(),
+            emoji: default_emoji.to_string(),
+            first_discovery: false,
+            discovered_at: None,
+            first_parent: None,
+            second_parent: None,
+        }
+        .insert(&pool)
+        .await?;
+        inserted += 1;
+    }
+
+    log::info!("Imported {inserted} elements, skipped {skipped} that already existed");
+    Ok(())
+}
+
+/// Every progress bar goes through this, so that the logger knows to print around them
+static PROGRESS_BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+fn progress_bar(length: u64) -> ProgressBar {
+    let bar = ProgressBar::new(length).with_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} [{elapsed_precise}, {eta} left]")
+            .unwrap(),
+    );
+    PROGRESS_BARS.add(bar)
+}
+
+const DATABASE_BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+ *  Read-only commands can't run migrations, so a database that hasn't been upgraded by a newer version yet is upgraded here first,
+ *  on a short-lived writable connection. Databases that are already up to date are never opened for writing
+ */
+async fn migrate_if_needed(db_path: &str) -> anyhow::Result<()> {
+    let connect_options = SqliteConnectOptions::new()
+        .filename(db_path)
+        .busy_timeout(DATABASE_BUSY_TIMEOUT);
+    let mut connection = connect_options
+        .clone()
+        .read_only(true)
+        .connect()
+        .await
+        .with_context(|| format!("Failed to open the database {db_path}"))?;
+    // A database from before migrations were tracked doesn't have the table at all
+    let applied =
+        sqlx::query_scalar::<_, i64>("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(&mut connection)
+            .await
+            .unwrap_or_default();
+    connection.close().await?;
 
-    let pool = SqlitePool::connect("sqlite:infinite-craft.db")
+    let migrator = sqlx::migrate!();
+    if migrator
+        .iter()
+        .all(|migration| applied.contains(&migration.version))
+    {
+        return Ok(());
+    }
+
+    log::info!("Upgrading the database to the latest version before reading it");
+    let mut connection = connect_options
+        .journal_mode(SqliteJournalMode::Wal)
+        .connect()
         .await
-        .unwrap();
+        .with_context(|| format!("Failed to open the database {db_path} to upgrade it"))?;
+    migrator
+        .run(&mut connection)
+        .await
+        .context("Failed to upgrade the database")?;
+    connection.close().await?;
+    Ok(())
+}
 
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
+    // Dependencies (sqlx especially) are very noisy at debug, so they stay at info at most
+    let level = cli.log_level.to_level_filter();
+    let logger = simple_logger::SimpleLogger::new()
+        .with_level(level.min(log::LevelFilter::Info))
+        .with_module_level(module_path!(), level);
+    // Log lines are printed above the progress bars, instead of getting mixed up with them
+    LogWrapper::new(PROGRESS_BARS.clone(), logger).try_init()?;
+    log::set_max_level(level);
+
+    let read_only = cli.command.is_read_only();
+    if read_only {
+        migrate_if_needed(&cli.db_path).await?;
+    }
+    // WAL lets the read-only commands run while `combine` is writing, and the busy timeout covers the brief moments where they do still block each other
+    let mut connect_options = SqliteConnectOptions::new()
+        .filename(&cli.db_path)
+        .read_only(read_only)
+        .create_if_missing(!read_only)
+        .busy_timeout(DATABASE_BUSY_TIMEOUT)
+        .foreign_keys(true)
+        // With WAL, NORMAL only syncs at checkpoints instead of on every commit, which keeps the combine loop's many small transactions cheap.
+        // The database can't be corrupted either way, but a power loss (not just a crash) can lose the last few combinations, which FULL wouldn't.
+        // Those are just pairs to request again, so the speed is worth it
+        .synchronous(SqliteSynchronous::Normal);
+    if !read_only {
+        // The journal mode is stored in the database file, so read-only connections pick it up without setting it
+        connect_options = connect_options.journal_mode(SqliteJournalMode::Wal);
+    }
+    let pool = SqlitePoolOptions::new()
+        .max_connections(cli.db_connections.get())
+        .connect_with(connect_options)
+        .await
+        .with_context(|| format!("Failed to open the database {}", cli.db_path))?;
+
+    if !read_only {
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .context("Failed to set up the database tables")?;
+    }
+
     match cli.command {
-        Command::Combine => do_combinations(pool).await,
-        Command::MergeExistingElements { elements_file_path } => {
-            merge_existing_elements(pool, &elements_file_path).await
+        Command::Combine(args) => {
+            let fetcher = ApiClient::new(args.client.clone())?;
+            do_combinations(pool, *args, cli.base_elements, &fetcher).await
+        }
+        Command::MergeExistingElements {
+            elements_file_path,
+            force,
+            dry_run,
+            batch_size,
+            case_fold,
+        } => {
+            merge_existing_elements(
+                pool,
+                &elements_file_path,
+                force,
+                dry_run,
+                batch_size,
+                case_fold,
+            )
+            .await
+        }
+        Command::SeedBase => {
+            let added = seed_base_elements(&pool).await?;
+            log::info!(
+                "Added {added} base elements, {} were already there",
+                BASE_ELEMENTS.len() - added
+            );
+            Ok(())
+        }
+        Command::ImportList {
+            path,
+            default_emoji,
+        } => import_list(pool, &path, &default_emoji).await,
+        Command::SerializeForPage {
+            output,
+            pretty,
+            append,
+            mark_all_discovered,
+            emoji,
+        } => serialize_for_page(pool, &output, pretty, append, mark_all_discovered, &emoji).await,
+        Command::ExportFiltered {
+            pattern,
+            output,
+            pretty,
+        } => export_filtered(pool, &pattern, &output, pretty).await,
+        Command::Stats { since, json } => print_stats(pool, since, json).await,
+        Command::Search {
+            query,
+            case_insensitive,
+        } => search_elements(pool, &query, case_insensitive).await,
+        Command::History { element } => print_history(pool, &element).await,
+        Command::Recipe { element } => print_recipes(pool, &element).await,
+        Command::Tree {
+            element,
+            max_depth,
+            random_recipe,
+            seed,
+        } => {
+            let base_elements = base_elements(&pool, cli.base_elements).await?;
+            print_element_tree(
+                pool,
+                &element,
+                max_depth,
+                random_recipe,
+                seed,
+                &base_elements,
+            )
+            .await
+        }
+        Command::Path { from, to } => {
+            let base_elements = base_elements(&pool, cli.base_elements).await?;
+            print_path(pool, &from, &to, &base_elements).await
+        }
+        Command::ExportDot {
+            output,
+            first_discovery_only,
+        } => export_dot(pool, &output, first_discovery_only).await,
+        Command::ExportCsv {
+            elements_path,
+            pairs_path,
+        } => export_csv(pool, &elements_path, &pairs_path).await,
+        Command::PruneNothing { confirm } => prune_nothing(pool, confirm).await,
+        Command::CheckIntegrity { fix } => check_integrity(pool, fix).await,
+        Command::DedupePairs => dedupe_pairs(pool).await,
+        Command::Leaderboard { limit } => print_leaderboard(pool, limit).await,
+        Command::Snapshot { label } => snapshot(pool, &cli.db_path, &label).await,
+        Command::Replay => replay(pool).await,
+        Command::Report { output, since } => write_report(pool, &output, since).await,
+        Command::TopByLength { longest, limit } => print_top_by_length(pool, longest, limit).await,
+        Command::Unused => print_unused(pool).await,
+        Command::Diff { other_db, full } => diff_databases(pool, &other_db, full).await,
+        Command::FixEmoji => fix_emoji(pool).await,
+        Command::CountPairsRemaining => print_pairs_remaining(pool).await,
+        Command::Pair {
+            first,
+            second,
+            client,
+        } => {
+            let fetcher = ApiClient::new(client)?;
+            combine_chain(pool, &[first, second], &fetcher).await
+        }
+        Command::Chain { elements, client } => {
+            let fetcher = ApiClient::new(client)?;
+            combine_chain(pool, &elements, &fetcher).await
+        }
+        Command::Verify {
+            sample,
+            delay_ms,
+            client,
+        } => {
+            let fetcher = ApiClient::new(client)?;
+            verify_pairs(pool, sample, Duration::from_millis(delay_ms), &fetcher).await
+        }
+        Command::RetryNothing {
+            limit,
+            delay_ms,
+            client,
+        } => {
+            let fetcher = ApiClient::new(client)?;
+            retry_nothing(pool, limit, Duration::from_millis(delay_ms), &fetcher).await
+        }
+        Command::SetBaseElements { elements } => set_base_elements(pool, &elements).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     *  Answers every pair with the same canned result
+     */
+    struct FakeFetcher {
+        result: Option<Element>,
+    }
+    impl PairFetcher for FakeFetcher {
+        async fn fetch(
+            &self,
+            _first: &str,
+            _second: &str,
+        ) -> Result<Option<Element>, PairRequestError> {
+            Ok(self.result.clone())
+        }
+    }
+
+    fn element(result: &str, emoji: &str, first_discovery: bool) -> Element {
+        Element {
+            result: result.to_string(),
+            emoji: emoji.to_string(),
+            first_discovery,
+            discovered_at: None,
+            first_parent: None,
+            second_parent: None,
+        }
+    }
+
+    async fn test_pool() -> SqlitePool {
+        // A single connection, since every connection to :memory: gets its own database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        seed_base_elements(&pool).await.unwrap();
+        pool
+    }
+
+    fn combine_args(extra: &[&str]) -> CombineArgs {
+        let discoveries_log = std::env::temp_dir().join("infinite-craft-test-discoveries.log");
+        let args = [
+            "infinite-craft",
+            "combine",
+            "--delay-ms",
+            "0",
+            "--discoveries-log",
+            discoveries_log.to_str().unwrap(),
+        ]
+        .into_iter()
+        .chain(extra.iter().copied());
+        match Cli::parse_from(args).command {
+            Command::Combine(args) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    async fn run_once(result: Option<Element>) -> (Elements, Pairs) {
+        let pool = test_pool().await;
+        let fetcher = FakeFetcher { result };
+        do_combinations(
+            pool.clone(),
+            combine_args(&["--count", "1"]),
+            None,
+            &fetcher,
+        )
+        .await
+        .unwrap();
+        load(&pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn runs_end_once_every_pair_has_been_tried() {
+        let pool = test_pool().await;
+        let fetcher = FakeFetcher { result: None };
+        // Only 10 pairs can be made from the base elements, fewer than the requests that can be out at once
+        do_combinations(
+            pool.clone(),
+            combine_args(&["--concurrency", "16"]),
+            None,
+            &fetcher,
+        )
+        .await
+        .unwrap();
+        let (_, pairs) = load(&pool).await.unwrap();
+        assert_eq!(pairs.len(), 10);
+    }
+
+    #[test]
+    fn several_emoji_are_valid() {
+        for emoji in ["🔥", "🌬️", "🎅🏼🦄", "😻💨", "🏴‍☠️", "#️⃣"] {
+            assert!(is_valid_emoji(emoji), "{emoji} should be valid");
+        }
+        for emoji in ["", " ", "Fire", "?"] {
+            assert!(!is_valid_emoji(emoji), "{emoji:?} should be invalid");
+        }
+    }
+
+    #[tokio::test]
+    async fn pair_order_does_not_matter() {
+        assert_eq!(
+            normalized_pair("Water", "Fire"),
+            normalized_pair("Fire", "Water")
+        );
+
+        let pool = test_pool().await;
+        insert_pair(&pool, "Water", "Fire", &Some("Steam".to_string()))
+            .await
+            .unwrap();
+        insert_pair(&pool, "Fire", "Water", &None).await.unwrap();
+        let (_, pairs) = load(&pool).await.unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(
+            pairs.get(&normalized_pair("Water", "Fire")),
+            pairs.get(&normalized_pair("Fire", "Water"))
+        );
+        assert_eq!(
+            pairs[&normalized_pair("Fire", "Water")].as_deref(),
+            Some("Steam")
+        );
+    }
+
+    #[derive(Debug)]
+    struct BusyError;
+    impl std::fmt::Display for BusyError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "database is locked")
+        }
+    }
+    impl std::error::Error for BusyError {}
+    impl sqlx::error::DatabaseError for BusyError {
+        fn message(&self) -> &str {
+            "database is locked"
+        }
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some("5".into())
+        }
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    #[tokio::test]
+    async fn sweeps_skip_pairs_the_options_rule_out() {
+        let pool = test_pool().await;
+        element("Lake", "🏞️", false).insert(&pool).await.unwrap();
+        let fetcher = FakeFetcher { result: None };
+        do_combinations(
+            pool.clone(),
+            combine_args(&[
+                "--sweep",
+                "Fire",
+                "--no-self-combine",
+                "--max-name-length",
+                "4",
+            ]),
+            None,
+            &fetcher,
+        )
+        .await
+        .unwrap();
+        let (_, pairs) = load(&pool).await.unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert!(!pairs.contains_key(&normalized_pair("Fire", "Fire")));
+        assert!(!pairs.contains_key(&normalized_pair("Fire", "Water")));
+    }
+
+    #[tokio::test]
+    async fn offline_rebuilds_from_the_base_elements() {
+        let pool = test_pool().await;
+        element("Steam", "💨", false).insert(&pool).await.unwrap();
+        element("Unreachable", "❓", false)
+            .insert(&pool)
+            .await
+            .unwrap();
+        insert_pair(&pool, "Water", "Fire", &Some("Steam".to_string()))
+            .await
+            .unwrap();
+        let (known_elements, pairs) = load(&pool).await.unwrap();
+
+        let mut elements = offline_start(&pool, &known_elements, None).await.unwrap();
+        assert_eq!(elements.len(), BASE_ELEMENTS.len());
+        assert_eq!(combine_offline(&mut elements, &known_elements, &pairs), 1);
+        assert!(elements.contains_key("Steam"));
+        assert!(!elements.contains_key("Unreachable"));
+    }
+
+    #[tokio::test]
+    async fn busy_writes_are_retried_until_they_succeed() {
+        let pool = test_pool().await;
+        let mut attempts = 0;
+        retry_busy(|| {
+            attempts += 1;
+            let busy = attempts < 3;
+            let pool = &pool;
+            async move {
+                if busy {
+                    return Err(sqlx::Error::Database(Box::new(BusyError)));
+                }
+                write_combination(pool, "Water", "Fire", &Some("Steam".to_string()), None).await
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts, 3);
+        let (_, pairs) = load(&pool).await.unwrap();
+        assert_eq!(
+            pairs[&normalized_pair("Water", "Fire")].as_deref(),
+            Some("Steam")
+        );
+    }
+
+    #[tokio::test]
+    async fn other_write_errors_are_not_retried() {
+        let mut attempts = 0;
+        let result = retry_busy(|| {
+            attempts += 1;
+            async { Err::<(), _>(sqlx::Error::RowNotFound) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn unexpected_responses_are_errors() {
+        let html = "<!DOCTYPE html><html><body>Something went wrong</body></html>";
+        assert!(matches!(
+            parse_pair_response(html),
+            Err(PairRequestError::InvalidResponse(body)) if body == html
+        ));
+        assert!(matches!(
+            parse_pair_response(r#"{"text": "Steam"}"#),
+            Err(PairRequestError::InvalidResponse(_))
+        ));
+
+        let long_body = "x".repeat(MAX_LOGGED_BODY_LENGTH * 2);
+        let Err(PairRequestError::InvalidResponse(body)) = parse_pair_response(&long_body) else {
+            panic!("Expected an invalid response error");
+        };
+        assert_eq!(body.len(), MAX_LOGGED_BODY_LENGTH);
+    }
+
+    #[test]
+    fn expected_responses_are_parsed() {
+        let steam = parse_pair_response(r#"{"result": "Steam", "emoji": "💨", "isNew": false}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(steam.result, "Steam");
+        assert_eq!(steam.emoji, "💨");
+        assert!(!steam.first_discovery);
+
+        let nothing = parse_pair_response(r#"{"result": "Nothing", "emoji": "", "isNew": false}"#);
+        assert!(matches!(nothing, Ok(None)));
+    }
+
+    #[test]
+    fn responses_without_is_new_are_not_first_discoveries() {
+        let steam = parse_pair_response(r#"{"result": "Steam", "emoji": "💨"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(steam.result, "Steam");
+        assert!(!steam.first_discovery);
+    }
+
+    #[test]
+    fn short_names_are_weighted_by_length() {
+        // The defaults
+        assert_eq!(length_weight("", 12, 2), 12);
+        assert_eq!(length_weight("Fire", 12, 2), 8);
+        assert_eq!(length_weight("Lighthouse", 12, 2), 2);
+        assert_eq!(length_weight("Ninjabread Space Pirate", 12, 2), 2);
+
+        // A steeper distribution
+        assert_eq!(length_weight("Fire", 30, 1), 26);
+        assert_eq!(length_weight("Ninjabread Space Pirate", 30, 1), 7);
+
+        // A flat one
+        assert_eq!(length_weight("Fire", 5, 5), 5);
+        assert_eq!(length_weight("Ninjabread Space Pirate", 5, 5), 5);
+    }
+
+    #[test]
+    fn pair_request_encodes_names() {
+        let client = build_client(&combine_args(&[]).client).unwrap();
+        let request = pair_request(&client, "Rock & Roll", "Fire")
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://neal.fun/api/infinite-craft/pair?first=Rock+%26+Roll&second=Fire"
+        );
+        let query = request.url().query_pairs().collect::<Vec<_>>();
+        assert_eq!(query[0].1, "Rock & Roll");
+        assert_eq!(query[1].1, "Fire");
+    }
+
+    #[test]
+    fn elements_round_trip_through_the_page_format() {
+        let elements = vec![element("Steam", "💨", true), element("Lava", "🌋", false)];
+        for original in elements {
+            let serialized = SerializedElement::from(original.clone());
+            assert_eq!(serialized.text, original.result);
+            assert_eq!(serialized.emoji, original.emoji);
+            assert!(serialized.discovered);
+
+            // The page doesn't know about first discoveries, so that's the one thing that doesn't survive the trip
+            let round_tripped = Element::from(serialized);
+            assert_eq!(round_tripped.result, original.result);
+            assert_eq!(round_tripped.emoji, original.emoji);
+            assert!(!round_tripped.first_discovery);
         }
-        Command::SerializeForPage => serialize_for_page(pool).await,
+    }
+
+    #[tokio::test]
+    async fn nothing_is_stored_as_a_pair_without_a_result() {
+        let (elements, pairs) = run_once(None).await;
+        assert_eq!(elements.len(), BASE_ELEMENTS.len());
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs.values().next().unwrap(), &None);
+    }
+
+    #[tokio::test]
+    async fn new_element_is_inserted_with_its_parents() {
+        let (elements, pairs) = run_once(Some(element("Steam", "💨", true))).await;
+        assert_eq!(elements.len(), BASE_ELEMENTS.len() + 1);
+        let ((first, second), result) = pairs.iter().next().unwrap();
+        assert_eq!(result.as_deref(), Some("Steam"));
+
+        let steam = &elements["Steam"];
+        assert!(steam.first_discovery);
+        assert_eq!(steam.first_parent.as_ref(), Some(first));
+        assert_eq!(steam.second_parent.as_ref(), Some(second));
+        assert!(steam.discovered_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn existing_element_only_records_the_pair() {
+        let (elements, pairs) = run_once(Some(element("Fire", "🔥", false))).await;
+        assert_eq!(elements.len(), BASE_ELEMENTS.len());
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs.values().next().unwrap().as_deref(), Some("Fire"));
+        assert_eq!(elements["Fire"].first_parent, None);
     }
 }